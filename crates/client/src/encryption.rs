@@ -1,52 +1,615 @@
 use aes_gcm::{
     AeadCore, Aes256Gcm,
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore},
 };
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use base64::engine::general_purpose;
 use base64::{Engine, engine::general_purpose::STANDARD};
-use keyring::Entry;
+use log::{info, warn};
 use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use zeroize::Zeroize;
+use toml_edit::{DocumentMut, value};
+use zeroize::{Zeroize, Zeroizing};
+use enva_shared::dotenv::Dotenv;
+use enva_shared::models::EnvFile;
+use crate::keystore;
+use crate::utils::{is_encrypted, key_generation, read_config, read_repo_config, write_repo_config};
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 const ITERATIONS: u32 = 100_000;
 const KEY_LEN: usize = 32; // 256 bits
+const SALT_LEN: usize = 16;
+pub const ARGON2_MEMORY_COST: u32 = 65_536; // KiB
+pub const ARGON2_TIME_COST: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
 const SALT: &str = "photon-hq/enva";
-const SERVICE: &str = "codes.photon.enva";
+const KEY_CHECK_INPUT: &str = "photon-hq/enva key check";
+// HKDF info of the subkeys value-only encryption derives from the repo key
+const VALUES_CIPHER_INFO: &str = "photon-hq/enva values cipher";
+const VALUES_MAC_INFO: &str = "photon-hq/enva values mac";
+const VALUES_NONCE_INFO: &str = "photon-hq/enva values nonce";
+const VALUES_MAC_LINE: &str = "\n# enva-mac: ";
+pub const SERVICE: &str = "codes.photon.enva";
+pub const KEY_ENV: &str = "ENVA_KEY";
+// Name under which a sealed snapshot is encrypted and signed
+pub const ARCHIVE_NAME: &str = "archive";
 
-pub fn save_pwd(repo_url: &str, password: &str) {
+// Envelope layout: MAGIC || VERSION || header length (u16 BE) || JSON header || ciphertext
+const MAGIC: &[u8; 4] = b"ENVA";
+// Version 2 binds the repo, file name and commit as associated data
+const VERSION: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kdf", rename_all = "kebab-case")]
+pub enum Kdf {
+    // Random data key, e.g. wrapped for recipients
+    None,
+    Pbkdf2Sha256 { iterations: u32, salt: String },
+    Argon2id { memory_cost: u32, time_cost: u32, parallelism: u32, salt: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum KdfKind {
+    Pbkdf2,
+    Argon2id,
+}
+
+impl Kdf {
+    pub fn new(kind: KdfKind, memory_cost: u32, time_cost: u32) -> Kdf {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let salt = STANDARD.encode(salt);
+
+        match kind {
+            KdfKind::Pbkdf2 => Kdf::Pbkdf2Sha256 {
+                iterations: ITERATIONS,
+                salt,
+            },
+            KdfKind::Argon2id => Kdf::Argon2id {
+                memory_cost,
+                time_cost,
+                parallelism: ARGON2_PARALLELISM,
+                salt,
+            },
+        }
+    }
+
+    pub fn kind(&self) -> Option<KdfKind> {
+        match self {
+            Kdf::None => None,
+            Kdf::Pbkdf2Sha256 { .. } => Some(KdfKind::Pbkdf2),
+            Kdf::Argon2id { .. } => Some(KdfKind::Argon2id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(flatten)]
+    kdf: Kdf,
+    nonce: String,
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    commit: Option<String>,
+    #[serde(default)]
+    generation: u32,
+}
+
+// `legacy` is set when the server still holds blobs from before the envelope format
+pub fn save_pwd(repo_url: &str, password: &str, kdf: &Kdf, key: Vec<u8>, legacy: bool) {
     let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
 
-    let key = Vec::from(derive_key(&owner, &repo_name, password));
+    let mut doc = read_repo_config();
+
+    if read_kdf(&doc).as_ref() != Some(kdf) {
+        write_kdf(&mut doc, kdf);
+        write_repo_config(doc);
+
+        info!("Updated key parameters in .enva.toml, commit it to share them with your team");
+    }
+
     save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain");
+
+    // Blobs written before the envelope format used a fixed salt, the weaker key is only kept while they exist
+    if legacy {
+        let legacy_key = Vec::from(derive_legacy_key(&owner, &repo_name, password));
+        save_derived_key(&owner, &format!("{repo_name}:legacy"), legacy_key)
+            .expect("Failed to save key to keychain");
+    } else {
+        keystore::delete(&format!("{owner}:{repo_name}:legacy")).expect("Failed to remove legacy key from keychain");
+    }
 }
 
-pub fn encrypt_string(repo_url: &str, plaintext: &str) -> String {
+pub fn encrypt_bytes(repo_url: &str, file_name: &str, commit_id: &str, plaintext: &[u8]) -> Vec<u8> {
     let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
 
-    let (ciphertext, nonce) = encrypt(&load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain"), plaintext.as_bytes());
+    let aad = associated_data(&owner, &repo_name, file_name, commit_id);
+    let (ciphertext, nonce) = encrypt(&load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain"), plaintext, &aad);
+
+    let header = Header {
+        kdf: read_kdf(&read_repo_config()).unwrap_or(Kdf::None),
+        nonce: STANDARD.encode(nonce),
+        file: Some(file_name.to_string()),
+        commit: Some(commit_id.to_string()),
+        generation: key_generation(repo_url),
+    };
+    let header = serde_json::to_vec(&header).expect("Failed to serialize envelope header");
 
-    // combine nonce + ciphertext
     let mut output = Vec::new();
-    output.extend_from_slice(&nonce);
+    output.extend_from_slice(MAGIC);
+    output.push(VERSION);
+    output.extend_from_slice(&(header.len() as u16).to_be_bytes());
+    output.extend_from_slice(&header);
     output.extend_from_slice(&ciphertext);
 
-    general_purpose::STANDARD.encode(output)
+    output
+}
+
+// `legacy` allows the pre-envelope format, which has no associated data binding the blob to its
+// repo, file and commit, so it is only set for blobs the server recorded in that format
+pub fn decrypt_bytes(repo_url: &str, file_name: &str, commit_id: &str, encrypted: &[u8], legacy: bool) -> Result<Vec<u8>, String> {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).ok_or("Invalid repo URL")?;
+
+    // Older clients stored the envelope base64-encoded
+    let data = match encrypted.starts_with(MAGIC) {
+        true => encrypted.to_vec(),
+        false => general_purpose::STANDARD
+            .decode(encrypted)
+            .map_err(|_| "Env file is not encrypted, was it committed without a password?")?,
+    };
+
+    let plaintext = match data.strip_prefix(MAGIC) {
+        Some(envelope) => {
+            let (header, ciphertext) = parse_envelope(envelope)?;
+
+            // The header is unauthenticated, but gives a clear error before the AEAD check fails
+            if let Some(file) = &header.file
+                && file != file_name
+            {
+                return Err(format!("Integrity check failed: the server returned the blob of {} for {}", file, file_name));
+            }
+
+            if let Some(commit) = &header.commit
+                && commit != commit_id
+            {
+                return Err(format!("Integrity check failed: the server returned a blob of commit {} for {}", commit, commit_id));
+            }
+
+            check_generation(repo_url, header.generation)?;
+
+            if let Some(kdf) = read_kdf(&read_repo_config())
+                && header.kdf != Kdf::None
+                && kdf != header.kdf
+            {
+                return Err("Snapshot was encrypted with different key parameters, run `enva active --password` again".to_string());
+            }
+
+            let nonce: [u8; 12] = STANDARD
+                .decode(&header.nonce)
+                .ok()
+                .and_then(|n| n.try_into().ok())
+                .ok_or("Invalid nonce in envelope header")?;
+
+            let key = load_derived_key(&owner, &repo_name).map_err(|e| format!("Failed to load key from keychain: {}", e))?;
+
+            decrypt(&key, ciphertext, &nonce, &associated_data(&owner, &repo_name, file_name, commit_id))
+                .map_err(|_| format!("Integrity check failed for {}: the blob doesn't belong to this repo, file and commit, or your key doesn't match", file_name))?
+        }
+        None => {
+            if !legacy {
+                return Err(format!(
+                    "Integrity check failed for {}: the blob isn't in the envelope format, and the server didn't record it as a legacy blob",
+                    file_name
+                ));
+            }
+
+            // Legacy format: nonce || ciphertext
+            if data.len() < 12 {
+                return Err("Truncated ciphertext".to_string());
+            }
+
+            let (nonce, ciphertext) = data.split_at(12);
+
+            // ENVA_KEY only ever holds the current key, so the legacy key comes from the key store alone
+            let key = stored_key(&owner, &format!("{repo_name}:legacy"))
+                .or_else(|_| load_derived_key(&owner, &repo_name))
+                .map_err(|e| format!("Failed to load key from keychain: {}", e))?;
+
+            decrypt(&key, ciphertext, nonce.try_into().unwrap(), &[])?
+        }
+    };
+
+    Ok(plaintext)
+}
+
+pub fn is_sealed(doc: &DocumentMut) -> bool {
+    doc.get("encryption")
+        .and_then(|e| e.get("seal"))
+        .and_then(|s| s.as_bool())
+        .unwrap_or(false)
+}
+
+pub fn is_values_mode(doc: &DocumentMut) -> bool {
+    doc.get("encryption")
+        .and_then(|e| e.get("values"))
+        .and_then(|s| s.as_bool())
+        .unwrap_or(false)
+}
+
+pub fn set_sealed() {
+    set_encryption_flag("seal");
+}
+
+pub fn set_values_mode() {
+    set_encryption_flag("values");
+}
+
+fn set_encryption_flag(flag: &str) {
+    let mut doc = read_repo_config();
+
+    if doc.get("encryption").is_none() {
+        doc["encryption"] = toml_edit::table();
+    }
+
+    doc["encryption"][flag] = value(true);
+    write_repo_config(doc);
+}
+
+// Encrypts only the values of a dotenv file, keeping keys, comments and layout
+// visible, and appends a MAC over the whole result. Values are encrypted deterministically,
+// so a value that didn't change keeps its ciphertext and only edited lines show up in diffs
+pub fn encrypt_values(repo_url: &str, file_name: &str, commit_id: &str, plaintext: &str) -> Result<String, String> {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+    let key = load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain");
+    let keys = ValuesKeys::derive(&key);
+    let generation = key_generation(repo_url);
+
+    let mut doc = Dotenv::parse(plaintext)?;
+
+    for entry in doc.entries_mut() {
+        // The commit isn't part of the associated data, the MAC binds the file to it instead
+        let aad = value_associated_data(&owner, &repo_name, file_name, entry.key());
+        let nonce = value_nonce(keys.nonce.as_slice(), &aad, entry.raw_value());
+        let ciphertext = encrypt_with_nonce(keys.cipher.as_slice(), entry.raw_value().as_bytes(), &nonce, &aad);
+
+        let mut data = Vec::from(nonce);
+        data.extend_from_slice(&ciphertext);
+
+        entry.set_raw_value(&format!("ENC[v1,{}]", STANDARD.encode(data)))?;
+    }
+
+    let body = doc.to_string();
+    let mac = values_mac(keys.mac.as_slice(), &owner, &repo_name, file_name, commit_id, generation, &body).finalize();
+
+    Ok(format!("{body}{VALUES_MAC_LINE}{generation}:{}", STANDARD.encode(mac.into_bytes())))
+}
+
+// An envelope, raw or base64-encoded by older clients, or a file with encrypted values
+pub fn is_encrypted_blob(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+        || is_values_encrypted(content)
+        || general_purpose::STANDARD
+            .decode(content)
+            .is_ok_and(|data| data.starts_with(MAGIC))
+}
+
+pub fn is_values_encrypted(content: &[u8]) -> bool {
+    content
+        .windows(VALUES_MAC_LINE.len())
+        .any(|window| window == VALUES_MAC_LINE.as_bytes())
+}
+
+pub fn decrypt_values(repo_url: &str, file_name: &str, commit_id: &str, content: &str) -> Result<String, String> {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).ok_or("Invalid repo URL")?;
+    let key = load_derived_key(&owner, &repo_name).map_err(|e| format!("Failed to load key from keychain: {}", e))?;
+    let keys = ValuesKeys::derive(&key);
+
+    let (body, trailer) = content.rsplit_once(VALUES_MAC_LINE).ok_or("Missing MAC")?;
+    let (generation, mac) = trailer.split_once(':').ok_or("Missing key generation")?;
+    let generation = generation.parse().map_err(|_| "Invalid key generation")?;
+
+    check_generation(repo_url, generation)?;
+
+    let mac = STANDARD.decode(mac.trim_end()).map_err(|_| "Invalid MAC")?;
+
+    values_mac(keys.mac.as_slice(), &owner, &repo_name, file_name, commit_id, generation, body)
+        .verify_slice(&mac)
+        .map_err(|_| "Integrity check failed: the file was modified or your key doesn't match".to_string())?;
+
+    let mut doc = Dotenv::parse(body)?;
+
+    for entry in doc.entries_mut() {
+        let data = entry
+            .raw_value()
+            .strip_prefix("ENC[v1,")
+            .and_then(|v| v.strip_suffix(']'))
+            .and_then(|v| STANDARD.decode(v).ok())
+            .filter(|data| data.len() >= 12)
+            .ok_or_else(|| format!("{} is not encrypted", entry.key()))?;
+
+        let (nonce, ciphertext) = data.split_at(12);
+        let aad = value_associated_data(&owner, &repo_name, file_name, entry.key());
+        let value = decrypt(keys.cipher.as_slice(), ciphertext, nonce.try_into().unwrap(), &aad)?;
+
+        entry.set_raw_value(&String::from_utf8(value).map_err(|e| e.to_string())?)?;
+    }
+
+    Ok(doc.to_string())
+}
+
+fn check_generation(repo_url: &str, generation: u32) -> Result<(), String> {
+    let local = key_generation(repo_url);
+
+    if generation != local {
+        return Err(format!(
+            "Encrypted with key generation {}, but your key is generation {}, run `enva active --password` with the current password",
+            generation, local
+        ));
+    }
+
+    Ok(())
+}
+
+// Covers the commit and key generation as well as the body, so neither can be swapped without detection
+fn values_mac(
+    key: &[u8],
+    owner: &str,
+    repo_name: &str,
+    file_name: &str,
+    commit_id: &str,
+    generation: u32,
+    body: &str,
+) -> Hmac<Sha256> {
+    let context = associated_data(owner, repo_name, file_name, commit_id);
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&(context.len() as u32).to_be_bytes());
+    mac.update(&context);
+    mac.update(&generation.to_be_bytes());
+    mac.update(body.as_bytes());
+
+    mac
+}
+
+// Value encryption, synthetic nonces and the file MAC each get their own key
+struct ValuesKeys {
+    cipher: Zeroizing<[u8; KEY_LEN]>,
+    nonce: Zeroizing<[u8; KEY_LEN]>,
+    mac: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl ValuesKeys {
+    fn derive(key: &[u8]) -> ValuesKeys {
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let expand = |info: &str| {
+            let mut subkey = Zeroizing::new([0u8; KEY_LEN]);
+            hkdf.expand(info.as_bytes(), subkey.as_mut()).expect("HKDF output fits");
+            subkey
+        };
+
+        ValuesKeys {
+            cipher: expand(VALUES_CIPHER_INFO),
+            nonce: expand(VALUES_NONCE_INFO),
+            mac: expand(VALUES_MAC_INFO),
+        }
+    }
+}
+
+// Returns the env files and sealed archive to upload, following the repo's encryption settings
+pub fn encrypt_snapshot(
+    repo_url: &str,
+    commit_id: &str,
+    env_files: HashMap<String, EnvFile>,
+) -> (HashMap<String, EnvFile>, Option<Vec<u8>>) {
+    let doc = read_repo_config();
+    let sealed = is_sealed(&doc);
+    let values_mode = is_values_mode(&doc);
+
+    if sealed && values_mode {
+        panic!("`seal` and `values` can't both be enabled in .enva.toml");
+    }
+
+    if !is_encrypted(repo_url) {
+        if sealed || values_mode {
+            panic!("Sealed and value-only snapshots require encryption, run `enva active --password` first");
+        }
+
+        return (env_files, None);
+    }
+
+    if sealed {
+        let archive = serde_json::to_vec(&env_files).expect("Failed to serialize snapshot");
+
+        return (HashMap::new(), Some(encrypt_bytes(repo_url, ARCHIVE_NAME, commit_id, &archive)));
+    }
+
+    let env_files = env_files
+        .into_iter()
+        .map(|(k, file)| {
+            // Binary files and files that aren't valid dotenv can't be split into values, so they are encrypted whole
+            let values = std::str::from_utf8(&file.content)
+                .ok()
+                .filter(|_| values_mode)
+                .map(|text| encrypt_values(repo_url, &k, commit_id, text));
+
+            let content = match values {
+                Some(Ok(content)) => content.into_bytes(),
+                Some(Err(e)) => {
+                    warn!("Encrypting {} whole: {}", k, e);
+                    encrypt_bytes(repo_url, &k, commit_id, &file.content)
+                }
+                None => encrypt_bytes(repo_url, &k, commit_id, &file.content),
+            };
+
+            (k, EnvFile { content, mode: file.mode })
+        })
+        .collect();
+
+    (env_files, None)
+}
+
+pub fn decrypt_snapshot(
+    repo_url: &str,
+    commit_id: &str,
+    env_files: HashMap<String, EnvFile>,
+    archive: Option<&[u8]>,
+    legacy_blobs: &[String],
+) -> Result<HashMap<String, EnvFile>, String> {
+    if let Some(archive) = archive {
+        // Sealed archives came after the envelope, so they are never legacy blobs
+        let archive = decrypt_bytes(repo_url, ARCHIVE_NAME, commit_id, archive, false)?;
+
+        // Archives written before binary support hold text contents, which EnvFile still accepts
+        return serde_json::from_slice::<HashMap<String, EnvFile>>(&archive).map_err(|e| e.to_string());
+    }
+
+    if !is_encrypted(repo_url) {
+        return Ok(env_files);
+    }
+
+    env_files
+        .into_iter()
+        .map(|(k, file)| {
+            let content = match is_values_encrypted(&file.content) {
+                false => decrypt_bytes(repo_url, &k, commit_id, &file.content, legacy_blobs.contains(&k)),
+                true => String::from_utf8(file.content)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| decrypt_values(repo_url, &k, commit_id, &text))
+                    .map(String::into_bytes),
+            };
+
+            content
+                .map(|content| (k.clone(), EnvFile { content, mode: file.mode }))
+                .map_err(|e| format!("{}: {}", k, e))
+        })
+        .collect()
+}
+
+pub fn key_check(key: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(KEY_CHECK_INPUT.as_bytes());
+
+    STANDARD.encode(mac.finalize().into_bytes())
 }
 
-pub fn decrypt_string(repo_url: &str, encrypted_b64: &str) -> String {
+pub fn repo_key_check(repo_url: &str) -> String {
     let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
 
-    let data = general_purpose::STANDARD.decode(encrypted_b64).unwrap();
+    key_check(&load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain"))
+}
+
+fn parse_envelope(envelope: &[u8]) -> Result<(Header, &[u8]), String> {
+    let (&version, rest) = envelope.split_first().ok_or("Truncated envelope")?;
+
+    if version > VERSION {
+        return Err(format!("Unsupported envelope version {}, please upgrade enva", version));
+    }
+
+    // Only the current version authenticates the header fields, earlier ones are never accepted
+    if version < VERSION {
+        return Err(format!("Unsupported envelope version {}", version));
+    }
+
+    if rest.len() < 2 {
+        return Err("Truncated envelope".to_string());
+    }
+
+    let (len, rest) = rest.split_at(2);
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+
+    if rest.len() < len {
+        return Err("Truncated envelope".to_string());
+    }
+
+    let (header, ciphertext) = rest.split_at(len);
+    let header: Header = serde_json::from_slice(header).map_err(|e| e.to_string())?;
+
+    Ok((header, ciphertext))
+}
+
+pub fn read_kdf(doc: &DocumentMut) -> Option<Kdf> {
+    let encryption = doc.get("encryption")?.as_table_like()?;
+
+    match encryption.get("kdf")?.as_str()? {
+        "pbkdf2-sha256" => Some(Kdf::Pbkdf2Sha256 {
+            iterations: encryption.get("iterations")?.as_integer()? as u32,
+            salt: encryption.get("salt")?.as_str()?.to_string(),
+        }),
+        "argon2id" => Some(Kdf::Argon2id {
+            memory_cost: encryption.get("memory_cost")?.as_integer()? as u32,
+            time_cost: encryption.get("time_cost")?.as_integer()? as u32,
+            parallelism: encryption.get("parallelism")?.as_integer()? as u32,
+            salt: encryption.get("salt")?.as_str()?.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+fn write_kdf(doc: &mut DocumentMut, kdf: &Kdf) {
+    if kdf == &Kdf::None {
+        return;
+    }
+
+    if doc.get("encryption").is_none() {
+        doc["encryption"] = toml_edit::table();
+    }
+
+    // Drop parameters of a previous KDF so they don't linger
+    if let Some(encryption) = doc.get_mut("encryption").and_then(|e| e.as_table_like_mut()) {
+        for key in ["kdf", "iterations", "memory_cost", "time_cost", "parallelism", "salt"] {
+            encryption.remove(key);
+        }
+    }
+
+    match kdf {
+        Kdf::None => {}
+        Kdf::Pbkdf2Sha256 { iterations, salt } => {
+            doc["encryption"]["kdf"] = value("pbkdf2-sha256");
+            doc["encryption"]["iterations"] = value(*iterations as i64);
+            doc["encryption"]["salt"] = value(salt.as_str());
+        }
+        Kdf::Argon2id { memory_cost, time_cost, parallelism, salt } => {
+            doc["encryption"]["kdf"] = value("argon2id");
+            doc["encryption"]["memory_cost"] = value(*memory_cost as i64);
+            doc["encryption"]["time_cost"] = value(*time_cost as i64);
+            doc["encryption"]["parallelism"] = value(*parallelism as i64);
+            doc["encryption"]["salt"] = value(salt.as_str());
+        }
+    }
+}
+
+pub fn derive_key(kdf: &Kdf, password: &str) -> Vec<u8> {
+    let mut key = vec![0u8; KEY_LEN];
 
-    let (nonce, ciphertext) = data.split_at(12);
+    match kdf {
+        Kdf::None => panic!("A password can't be used with a random data key"),
+        Kdf::Pbkdf2Sha256 { iterations, salt } => {
+            let salt = STANDARD.decode(salt).expect("Invalid salt in .enva.toml");
+            pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, *iterations, &mut key);
+        }
+        Kdf::Argon2id { memory_cost, time_cost, parallelism, salt } => {
+            let salt = STANDARD.decode(salt).expect("Invalid salt in .enva.toml");
+            let params = Params::new(*memory_cost, *time_cost, *parallelism, Some(KEY_LEN))
+                .expect("Invalid Argon2id parameters in .enva.toml");
 
-    let plaintext = decrypt(&load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain"), ciphertext, nonce.try_into().unwrap());
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password.as_bytes(), &salt, &mut key)
+                .expect("Failed to derive key with Argon2id");
+        }
+    }
 
-    String::from_utf8(plaintext).unwrap()
+    key
 }
 
-fn derive_key(owner: &str, repo_name: &str, password: &str) -> [u8; KEY_LEN] {
+fn derive_legacy_key(owner: &str, repo_name: &str, password: &str) -> [u8; KEY_LEN] {
     let effective_salt = format!("{owner}:{repo_name}:{SALT}");
     let mut key = [0u8; KEY_LEN];
 
@@ -60,27 +623,49 @@ fn derive_key(owner: &str, repo_name: &str, password: &str) -> [u8; KEY_LEN] {
     key
 }
 
-fn encrypt(key: &[u8], plaintext: &[u8]) -> (Vec<u8>, [u8; 12]) {
-    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+fn associated_data(owner: &str, repo_name: &str, file_name: &str, commit_id: &str) -> Vec<u8> {
+    serde_json::to_vec(&[format!("{owner}/{repo_name}").as_str(), file_name, commit_id])
+        .expect("Failed to serialize associated data")
+}
 
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let ciphertext = cipher.encrypt(&nonce, plaintext).unwrap();
+fn value_associated_data(owner: &str, repo_name: &str, file_name: &str, name: &str) -> Vec<u8> {
+    serde_json::to_vec(&[format!("{owner}/{repo_name}").as_str(), file_name, name])
+        .expect("Failed to serialize associated data")
+}
+
+// Synthetic nonce: a nonce only repeats for the same value under the same associated data,
+// which then yields the same ciphertext and reveals nothing beyond the equality
+fn value_nonce(key: &[u8], aad: &[u8], value: &str) -> [u8; 12] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&(aad.len() as u32).to_be_bytes());
+    mac.update(aad);
+    mac.update(value.as_bytes());
 
-    (ciphertext, nonce.into())
+    mac.finalize().into_bytes()[..12].try_into().unwrap()
 }
 
-fn decrypt(key: &[u8], ciphertext: &[u8], nonce: &[u8; 12]) -> Vec<u8> {
+fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> (Vec<u8>, [u8; 12]) {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng).into();
+
+    (encrypt_with_nonce(key, plaintext, &nonce, aad), nonce)
+}
+
+fn encrypt_with_nonce(key: &[u8], plaintext: &[u8], nonce: &[u8; 12], aad: &[u8]) -> Vec<u8> {
     let cipher = Aes256Gcm::new_from_slice(key).unwrap();
-    cipher
-        .decrypt(nonce.into(), ciphertext)
-        .unwrap()
+
+    cipher.encrypt(nonce.into(), Payload { msg: plaintext, aad }).unwrap()
 }
 
-fn save_derived_key(owner: &str, repo_name: &str, mut key: Vec<u8>) -> Result<(), keyring::Error> {
-    let entry = Entry::new(SERVICE, format!("{owner}:{repo_name}").as_str())?;
+fn decrypt(key: &[u8], ciphertext: &[u8], nonce: &[u8; 12], aad: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+    cipher
+        .decrypt(nonce.into(), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Failed to decrypt, your key doesn't match the one this snapshot was encrypted with".to_string())
+}
 
+pub fn save_derived_key(owner: &str, repo_name: &str, mut key: Vec<u8>) -> Result<(), String> {
     let encoded = STANDARD.encode(&key);
-    entry.set_password(&encoded)?;
+    keystore::set(&format!("{owner}:{repo_name}"), &encoded)?;
 
     // wipe key from memory after storing
     key.zeroize();
@@ -88,9 +673,25 @@ fn save_derived_key(owner: &str, repo_name: &str, mut key: Vec<u8>) -> Result<()
     Ok(())
 }
 
-fn load_derived_key(owner: &str, repo_name: &str) -> Result<Vec<u8>, keyring::Error> {
-    let entry = Entry::new(SERVICE, format!("{owner}:{repo_name}").as_str())?;
-    let encoded = entry.get_password()?;
+pub fn load_derived_key(owner: &str, repo_name: &str) -> Result<Vec<u8>, String> {
+    // CI can hand the key over directly, without any key store
+    if let Ok(encoded) = env::var(KEY_ENV) {
+        return STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .ok_or_else(|| format!("{} must hold a base64-encoded {}-byte key", KEY_ENV, KEY_LEN));
+    }
+
+    if let Some(path) = key_file(owner, repo_name) {
+        return read_key_file(&path);
+    }
+
+    stored_key(owner, repo_name)
+}
+
+fn stored_key(owner: &str, repo_name: &str) -> Result<Vec<u8>, String> {
+    let encoded = keystore::get(&format!("{owner}:{repo_name}"))?;
 
     let key = STANDARD
         .decode(encoded)
@@ -98,3 +699,190 @@ fn load_derived_key(owner: &str, repo_name: &str) -> Result<Vec<u8>, keyring::Er
 
     Ok(key)
 }
+
+// Wraps and unwraps repo data keys with an external secret manager, so the key never
+// has to be derived from a password
+pub trait KeyProvider {
+    fn wrap(&self, repo: &str, key: &[u8]) -> Result<String, String>;
+    fn unwrap(&self, repo: &str, wrapped: &str) -> Result<Vec<u8>, String>;
+}
+
+// Runs a user-configured helper the way `git credential` does: the action (`wrap` or `unwrap`)
+// is appended to the command, and `name=value` lines are exchanged over stdin and stdout
+pub struct CommandProvider {
+    pub command: String,
+}
+
+impl CommandProvider {
+    fn run(&self, action: &str, input: &[(&str, &str)]) -> Result<HashMap<String, String>, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} {}", self.command, action))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("Failed to run key command: {}", e))?;
+
+        let mut request: String = input.iter().map(|(name, value)| format!("{name}={value}\n")).collect();
+        request.push('\n');
+
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(request.as_bytes())
+            .map_err(|e| format!("Failed to write to key command: {}", e))?;
+
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+
+        if !output.status.success() {
+            return Err(format!("Key command `{}` failed with {}", action, output.status));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect())
+    }
+}
+
+impl KeyProvider for CommandProvider {
+    fn wrap(&self, repo: &str, key: &[u8]) -> Result<String, String> {
+        let encoded = STANDARD.encode(key);
+
+        self.run("wrap", &[("repo", repo), ("key", &encoded)])?
+            .remove("wrapped")
+            .ok_or_else(|| "Key command didn't return a wrapped= line".to_string())
+    }
+
+    fn unwrap(&self, repo: &str, wrapped: &str) -> Result<Vec<u8>, String> {
+        let encoded = self
+            .run("unwrap", &[("repo", repo), ("wrapped", wrapped)])?
+            .remove("key")
+            .ok_or("Key command didn't return a key= line")?;
+
+        STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .filter(|key| key.len() == KEY_LEN)
+            .ok_or_else(|| format!("Key command must return a base64-encoded {}-byte key", KEY_LEN))
+    }
+}
+
+// The command lives in the local config.toml rather than .enva.toml, so pulling a repo can't run code
+pub fn key_provider() -> Option<Box<dyn KeyProvider>> {
+    read_config()
+        .get("key_provider")
+        .and_then(|provider| provider.get("command"))
+        .and_then(|command| command.as_str())
+        .map(|command| Box::new(CommandProvider { command: command.to_string() }) as Box<dyn KeyProvider>)
+}
+
+pub fn read_provider_wrapped_key(doc: &DocumentMut) -> Option<String> {
+    doc.get("key_provider")
+        .and_then(|provider| provider.get("wrapped_key"))
+        .and_then(|wrapped| wrapped.as_str())
+        .map(str::to_string)
+}
+
+pub fn write_provider_wrapped_key(doc: &mut DocumentMut, wrapped: &str) {
+    if doc.get("key_provider").is_none() {
+        doc["key_provider"] = toml_edit::table();
+    }
+
+    doc["key_provider"]["wrapped_key"] = value(wrapped);
+}
+
+pub fn key_file(owner: &str, repo_name: &str) -> Option<PathBuf> {
+    read_config()
+        .get(&format!("{owner}:{repo_name}"))
+        .and_then(|repo| repo.get("key_file"))
+        .and_then(|path| path.as_str())
+        .map(PathBuf::from)
+}
+
+// Accepts the raw 32 bytes or their base64 encoding
+pub fn read_key_file(path: &Path) -> Result<Vec<u8>, String> {
+    let content = std::fs::read(path).map_err(|e| format!("Failed to read key file {}: {}", path.display(), e))?;
+
+    let key = match content.len() {
+        KEY_LEN => content,
+        _ => STANDARD
+            .decode(String::from_utf8_lossy(&content).trim())
+            .map_err(|_| format!("Key file {} is neither 32 raw bytes nor base64", path.display()))?,
+    };
+
+    if key.len() != KEY_LEN {
+        return Err(format!("Key file {} must hold a {}-byte key", path.display(), KEY_LEN));
+    }
+
+    Ok(key)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // Writes `body` as a helper script, run through sh rather than executed so a parallel test
+    // forking mid-write can't fail the exec with ETXTBSY
+    fn provider(name: &str, body: &str) -> CommandProvider {
+        let dir = env::temp_dir().join(format!("enva-key-command-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, body).unwrap();
+
+        CommandProvider { command: format!("sh '{}'", path.display()) }
+    }
+
+    const READ_REQUEST: &str = r#"while IFS= read -r line; do
+  [ -z "$line" ] && break
+  case $line in repo=*) repo=${line#repo=} ;; key=*) key=${line#key=} ;; wrapped=*) wrapped=${line#wrapped=} ;; esac
+done
+"#;
+
+    #[test]
+    fn wrap_and_unwrap_round_trip() {
+        let provider = provider("round-trip.sh", &format!(r#"{READ_REQUEST}
+case $1 in
+  wrap) echo "note=ignored"; echo "wrapped=$repo|$key" ;;
+  unwrap) echo "key=${{wrapped#*|}}" ;;
+esac
+"#));
+
+        let key = [7u8; KEY_LEN];
+        let wrapped = provider.wrap("owner/repo", &key).unwrap();
+
+        assert_eq!(wrapped, format!("owner/repo|{}", STANDARD.encode(key)));
+        assert_eq!(provider.unwrap("owner/repo", &wrapped).unwrap(), key);
+    }
+
+    #[test]
+    fn missing_output_lines() {
+        let provider = provider("silent.sh", &format!("{READ_REQUEST}echo unrelated=1\n"));
+
+        assert_eq!(provider.wrap("owner/repo", &[0; KEY_LEN]).unwrap_err(), "Key command didn't return a wrapped= line");
+        assert_eq!(provider.unwrap("owner/repo", "sealed").unwrap_err(), "Key command didn't return a key= line");
+    }
+
+    #[test]
+    fn non_zero_exit() {
+        let provider = provider("failing.sh", &format!("{READ_REQUEST}echo wrapped=ignored\nexit 3\n"));
+
+        let error = provider.wrap("owner/repo", &[0; KEY_LEN]).unwrap_err();
+        assert!(error.starts_with("Key command `wrap` failed with"), "{}", error);
+        assert!(error.contains('3'), "{}", error);
+    }
+
+    #[test]
+    fn wrong_key_length() {
+        let provider = provider("short-key.sh", &format!("{READ_REQUEST}echo key={}\n", STANDARD.encode([1u8; 16])));
+
+        assert_eq!(
+            provider.unwrap("owner/repo", "sealed").unwrap_err(),
+            format!("Key command must return a base64-encoded {}-byte key", KEY_LEN)
+        );
+    }
+}
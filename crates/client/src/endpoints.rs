@@ -1,7 +1,7 @@
 use crate::utils::get_token;
 use log::{error};
 use reqwest::{Error, Response};
-use enva_shared::models::{CommitRequest, CommitResponse, FetchRequest, FetchResponse, CheckCommitRequest, CheckCommitResponse};
+use enva_shared::models::{CommitRequest, CommitResponse, FetchRequest, FetchResponse, CheckCommitRequest, CheckCommitResponse, ListRequest, ListResponse, BranchesRequest, BranchesResponse, KeyCheckRequest, KeyCheckResponse, RekeyRequest, RekeyResponse, RollbackRequest, RollbackResponse};
 use serde::de::DeserializeOwned;
 
 const BASE_URL: &str = if let Some(url) = option_env!("BASE_URL") {
@@ -72,3 +72,68 @@ pub async fn call_check(req: CheckCommitRequest) -> Option<CheckCommitResponse>
 
     parse_response::<CheckCommitResponse>(res).await
 }
+
+pub async fn call_list(req: ListRequest) -> Option<ListResponse> {
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{}/list", BASE_URL))
+        .bearer_auth(get_token().expect("Failed to get token"))
+        .json(&req)
+        .send()
+        .await;
+
+    parse_response::<ListResponse>(res).await
+}
+
+pub async fn call_branches(req: BranchesRequest) -> Option<BranchesResponse> {
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{}/branches", BASE_URL))
+        .bearer_auth(get_token().expect("Failed to get token"))
+        .json(&req)
+        .send()
+        .await;
+
+    parse_response::<BranchesResponse>(res).await
+}
+
+pub async fn call_key_check(req: KeyCheckRequest) -> Option<KeyCheckResponse> {
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{}/key-check", BASE_URL))
+        .bearer_auth(get_token().expect("Failed to get token"))
+        .json(&req)
+        .send()
+        .await;
+
+    parse_response::<KeyCheckResponse>(res).await
+}
+
+pub async fn call_rekey(req: RekeyRequest) -> Option<RekeyResponse> {
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{}/rekey", BASE_URL))
+        .bearer_auth(get_token().expect("Failed to get token"))
+        .json(&req)
+        .send()
+        .await;
+
+    parse_response::<RekeyResponse>(res).await
+}
+
+pub async fn call_rollback(req: RollbackRequest) -> Option<RollbackResponse> {
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(format!("{}/rollback", BASE_URL))
+        .bearer_auth(get_token().expect("Failed to get token"))
+        .json(&req)
+        .send()
+        .await;
+
+    parse_response::<RollbackResponse>(res).await
+}
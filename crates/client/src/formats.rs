@@ -0,0 +1,290 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use enva_shared::dotenv::{Dotenv, is_valid_key};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Yaml,
+    Shell,
+    DockerEnv,
+    K8sSecret,
+    Systemd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ImportFormat {
+    Json,
+    Yaml,
+    K8sSecret,
+    Dotenv,
+}
+
+impl ImportFormat {
+    // Guessed from the extension; a YAML file holding a Secret manifest is detected when parsed
+    pub fn detect(path: &Path) -> ImportFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ImportFormat::Json,
+            Some("yaml" | "yml") => ImportFormat::Yaml,
+            _ => ImportFormat::Dotenv,
+        }
+    }
+}
+
+// Renders resolved variables, without duplicates, into `format`. Shell, docker-env and systemd keep
+// the given load order; json, yaml and k8s-secret are sorted by name. `name` is the metadata.name of a Kubernetes Secret.
+pub fn render(format: ExportFormat, vars: &[(String, String)], name: &str) -> Result<String, String> {
+    let map: BTreeMap<&str, &str> = vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&map).map(|json| json + "\n").map_err(|e| e.to_string()),
+        ExportFormat::Yaml => serde_yaml::to_string(&map).map_err(|e| e.to_string()),
+        ExportFormat::Shell => Ok(vars
+            .iter()
+            .map(|(k, v)| format!("export {}='{}'\n", k, v.replace('\'', "'\\''")))
+            .collect()),
+        ExportFormat::DockerEnv => vars
+            .iter()
+            .map(|(k, v)| single_line(k, v, "docker-env").map(|v| format!("{k}={v}\n")))
+            .collect(),
+        ExportFormat::K8sSecret => {
+            if !is_dns_subdomain(name) {
+                return Err(format!(
+                    "`{}` isn't a valid Secret name, use lowercase letters, digits, '-' and '.' starting and ending with a letter or digit",
+                    name
+                ));
+            }
+
+            let data: BTreeMap<&str, String> = map.iter().map(|(k, v)| (*k, STANDARD.encode(v))).collect();
+
+            serde_yaml::to_string(&json!({
+                "apiVersion": "v1",
+                "kind": "Secret",
+                "metadata": { "name": name },
+                "type": "Opaque",
+                "data": data,
+            }))
+            .map_err(|e| e.to_string())
+        }
+        ExportFormat::Systemd => vars
+            .iter()
+            .map(|(k, v)| {
+                single_line(k, v, "systemd")
+                    .map(|v| format!("{}=\"{}\"\n", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            })
+            .collect(),
+    }
+}
+
+// Kubernetes object names are DNS-1123 subdomains
+const MAX_SECRET_NAME: usize = 253;
+
+fn is_dns_subdomain(name: &str) -> bool {
+    name.len() <= MAX_SECRET_NAME
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
+// Derives a Secret name from a repo name, e.g. `My_Repo.rs` becomes `my-repo-rs`
+pub fn secret_name(repo_name: &str) -> Option<String> {
+    let mut name = String::new();
+
+    for c in repo_name.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+
+    name.truncate(MAX_SECRET_NAME);
+    let name = name.trim_end_matches('-');
+
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// docker's --env-file and systemd's EnvironmentFile have no way to write a line break in a value
+fn single_line<'a>(key: &str, value: &'a str, format: &str) -> Result<&'a str, String> {
+    if value.contains(['\n', '\r']) {
+        return Err(format!("{} spans several lines, which {} can't represent", key, format));
+    }
+
+    Ok(value)
+}
+
+// Reads variables from `content`, in file order for dotenv and key order otherwise
+pub fn parse(format: ImportFormat, content: &str) -> Result<Vec<(String, String)>, String> {
+    let vars = match format {
+        ImportFormat::Json => flat_map(serde_json::from_str(content).map_err(|e| e.to_string())?)?,
+        ImportFormat::Yaml => {
+            let value: Value = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+
+            if value.get("kind").and_then(Value::as_str) == Some("Secret") {
+                secret_data(value)?
+            } else {
+                flat_map(value)?
+            }
+        }
+        ImportFormat::K8sSecret => secret_data(serde_yaml::from_str(content).map_err(|e| e.to_string())?)?,
+        ImportFormat::Dotenv => Dotenv::parse(content)?.resolve(|_| None),
+    };
+
+    if let Some((key, _)) = vars.iter().find(|(key, _)| !is_valid_key(key)) {
+        return Err(format!("`{}` isn't a valid variable name", key));
+    }
+
+    Ok(vars)
+}
+
+fn flat_map(value: Value) -> Result<Vec<(String, String)>, String> {
+    let Value::Object(map) = value else {
+        return Err("Expected a mapping of names to values".to_string());
+    };
+
+    map.into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            Value::Number(_) | Value::Bool(_) => Ok((key, value.to_string())),
+            Value::Null => Ok((key, String::new())),
+            _ => Err(format!("{} isn't a plain value", key)),
+        })
+        .collect()
+}
+
+// `data` holds base64 values, `stringData` plain ones that take precedence, as with kubectl
+fn secret_data(mut secret: Value) -> Result<Vec<(String, String)>, String> {
+    if secret.get("kind").and_then(Value::as_str) != Some("Secret") {
+        return Err("Expected a Kubernetes Secret manifest".to_string());
+    }
+
+    let mut vars = match secret.get_mut("data").map(Value::take) {
+        Some(data) => flat_map(data)?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = STANDARD
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|value| String::from_utf8(value).ok())
+                    .ok_or_else(|| format!("{} isn't valid base64 text", key))?;
+
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>, String>>()?,
+        None => Vec::new(),
+    };
+
+    if let Some(data) = secret.get_mut("stringData").map(Value::take) {
+        for (key, value) in flat_map(data)? {
+            vars.retain(|(k, _)| *k != key);
+            vars.push((key, value));
+        }
+    }
+
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vec<(String, String)> {
+        [("ZED", "last"), ("API_KEY", "it's \"quoted\""), ("EMPTY", ""), ("URL", "https://x.test/?a=1&b=2")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn sorted(mut vars: Vec<(String, String)>) -> Vec<(String, String)> {
+        vars.sort();
+        vars
+    }
+
+    #[test]
+    fn json_and_yaml_round_trip_sorted() {
+        for (export, import) in [(ExportFormat::Json, ImportFormat::Json), (ExportFormat::Yaml, ImportFormat::Yaml)] {
+            let rendered = render(export, &vars(), "").unwrap();
+
+            assert_eq!(parse(import, &rendered).unwrap(), sorted(vars()));
+        }
+    }
+
+    #[test]
+    fn k8s_secret_round_trips() {
+        let rendered = render(ExportFormat::K8sSecret, &vars(), "my-repo").unwrap();
+
+        assert!(rendered.contains("name: my-repo"));
+        assert_eq!(parse(ImportFormat::K8sSecret, &rendered).unwrap(), sorted(vars()));
+        // Secrets are also recognised in a plain .yaml file
+        assert_eq!(parse(ImportFormat::Yaml, &rendered).unwrap(), sorted(vars()));
+    }
+
+    #[test]
+    fn k8s_secret_rejects_invalid_names() {
+        for name in ["", "My_Repo", "-repo", "repo-", "a..b", &"a".repeat(254)] {
+            assert!(render(ExportFormat::K8sSecret, &vars(), name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn string_data_overrides_data() {
+        let secret = "kind: Secret\ndata:\n  A: b25l\n  B: dHdv\nstringData:\n  A: plain\n";
+
+        assert_eq!(
+            parse(ImportFormat::K8sSecret, secret).unwrap(),
+            vec![("B".to_string(), "two".to_string()), ("A".to_string(), "plain".to_string())]
+        );
+    }
+
+    #[test]
+    fn line_formats_keep_load_order() {
+        let shell = render(ExportFormat::Shell, &vars(), "").unwrap();
+        assert!(shell.starts_with("export ZED='last'\n"));
+        assert!(shell.contains("export API_KEY='it'\\''s \"quoted\"'\n"));
+
+        let docker = render(ExportFormat::DockerEnv, &vars(), "").unwrap();
+        assert_eq!(docker.lines().next(), Some("ZED=last"));
+
+        let systemd = render(ExportFormat::Systemd, &vars(), "").unwrap();
+        assert!(systemd.contains("API_KEY=\"it's \\\"quoted\\\"\"\n"));
+    }
+
+    #[test]
+    fn single_line_formats_reject_line_breaks() {
+        let vars = vec![("CERT".to_string(), "a\nb".to_string())];
+
+        assert!(render(ExportFormat::DockerEnv, &vars, "").is_err());
+        assert!(render(ExportFormat::Systemd, &vars, "").is_err());
+        assert!(render(ExportFormat::Shell, &vars, "").is_ok());
+    }
+
+    #[test]
+    fn dotenv_keeps_file_order() {
+        let vars = parse(ImportFormat::Dotenv, "B=1\nA=${B}2\n").unwrap();
+
+        assert_eq!(vars, vec![("B".to_string(), "1".to_string()), ("A".to_string(), "12".to_string())]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_names_and_nested_values() {
+        assert!(parse(ImportFormat::Json, r#"{"1BAD": "x"}"#).is_err());
+        assert!(parse(ImportFormat::Json, r#"{"A": {"B": "x"}}"#).is_err());
+        assert_eq!(
+            parse(ImportFormat::Json, r#"{"N": 1, "T": true, "E": null}"#).unwrap(),
+            vec![("E".to_string(), String::new()), ("N".to_string(), "1".to_string()), ("T".to_string(), "true".to_string())]
+        );
+    }
+
+    #[test]
+    fn secret_names_from_repo_names() {
+        assert_eq!(secret_name("My_Repo.rs").as_deref(), Some("my-repo-rs"));
+        assert_eq!(secret_name("__enva--").as_deref(), Some("enva"));
+        assert_eq!(secret_name("___"), None);
+        assert_eq!(secret_name(&"a".repeat(300)).map(|name| name.len()), Some(MAX_SECRET_NAME));
+    }
+}
@@ -1,15 +1,42 @@
-use crate::{endpoints, ActiveArgs, LoginArgs};
+use crate::{
+    endpoints, ActiveArgs, KeyGenerateArgs, KeyRecoverArgs, KeyShowArgs, KeySplitArgs, LoginArgs, RecipientAddArgs, RecipientRemoveArgs,
+    ExportArgs, FetchArgs, GetArgs, HistoryArgs, ImportArgs, RekeyArgs, RollbackArgs, RunArgs, SetArgs, SignerAddArgs, SignerRemoveArgs, UnsetArgs,
+};
 use crate::utils::{
-    check_ownership, get_enva_executable_path, get_repo_url, read_config, read_env_file,
-    write_config, write_git_hook,
+    check_ownership, check_synced_file, file_policy, get_enva_executable_path, get_repo_url, get_token, is_encrypted, read_config, read_dotenv, read_env_file,
+    read_repo_config, set_key_generation, write_config, write_dotenv, write_env_file, write_git_hook, write_repo_config,
 };
-use log::{error, info};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::io::Read;
+use std::path::Path;
 use std::process::Command;
 use git2::Repository;
-use toml_edit::value;
-use enva_shared::models::{CheckCommitRequest, CommitRequest, FetchRequest};
-use crate::encryption::{decrypt_string, encrypt_string, save_pwd};
+use enva_shared::dotenv::{Dotenv, is_valid_key};
+use toml_edit::{DocumentMut, value};
+use clap::ValueEnum;
+use enva_shared::models::{
+    BranchInfo, BranchesRequest, CheckCommitRequest, CommitInfo, CommitRequest, EnvFile, FetchRequest, KeyCheckRequest, ListRequest, Manifest,
+    RekeyRequest, RekeySnapshot, RollbackRequest, SignedManifest,
+};
+use crate::encryption::{
+    KEY_ENV, Kdf, KdfKind, decrypt_snapshot, is_encrypted_blob, derive_key, encrypt_snapshot, key_check, key_file, key_provider, load_derived_key, read_kdf, read_key_file, read_provider_wrapped_key,
+    repo_key_check, save_derived_key, save_pwd, set_sealed, set_values_mode, write_provider_wrapped_key,
+};
 use crate::endpoints::call_check;
+use crate::keystore;
+use crate::passphrase::{PASSWORD_ENV, prompt_password, read_password};
+use crate::formats::{ExportFormat, ImportFormat, parse, render, secret_name};
+use crate::shares::{recover_key, share_threshold, split_key};
+use crate::manifest::{
+    Signer, build_manifest, generate_signing_key, load_keychain_signing_key, load_signing_key, read_signers,
+    sign, ssh_public_key_to_signer, verify_signature, verify_snapshot, verifying_key, write_signers,
+};
+use crate::recipients::{
+    Recipient, generate_data_key, generate_identity, load_identities, load_identity, public_key,
+    read_recipients, ssh_public_key_to_recipient, unwrap_repo_key, wrap_key, write_recipients,
+};
 
 pub(crate) fn login(args: LoginArgs) {
     let mut token = args.token.unwrap_or_default();
@@ -44,22 +71,120 @@ pub(crate) fn login(args: LoginArgs) {
 pub async fn active(args: ActiveArgs) {
     check_ownership().await;
 
-    if let Some(password) = args.password {
+    // ENVA_PASSWORD would otherwise win over the key source asked for on the command line
+    if (args.key_file.is_some() || args.key_provider) && env::var(PASSWORD_ENV).is_ok() {
+        panic!("{} is set, unset it to use --key-file or --key-provider", PASSWORD_ENV);
+    }
+
+    // A repo without key parameters is being encrypted for the first time, so the password is confirmed
+    let is_new = read_kdf(&read_repo_config()).is_none();
+    let password = read_password(args.password, args.password_stdin, "Repo password: ", is_new);
+
+    if let Some(password) = password {
+        let kdf = match (read_kdf(&read_repo_config()), args.kdf) {
+            (Some(kdf), None) => kdf,
+            (Some(kdf), Some(kind)) if kdf.kind() == Some(kind) => kdf,
+            (Some(_), Some(kind)) => panic!(
+                "This repo uses a different KDF, run `enva rekey --kdf {}` to switch",
+                kind.to_possible_value().expect("KdfKind has no skipped values").get_name()
+            ),
+            (None, kind) => Kdf::new(kind.unwrap_or(KdfKind::Pbkdf2), args.memory_cost, args.time_cost),
+        };
+
+        let key = derive_key(&kdf, &password);
+        let legacy_blobs = verify_key(&get_repo_url(), &key).await;
+
         info!("Saving password to keychain");
 
-        save_pwd(&get_repo_url(), &password);
+        save_pwd(&get_repo_url(), &password, &kdf, key, legacy_blobs);
 
         info!("Password saved successfully");
 
-        let (owner, repo_name) = enva_shared::parse_github_repo(&get_repo_url()).expect("Invalid repo URL");
+        set_encrypted(&get_repo_url());
+    } else if let Some(key_file) = args.key_file {
+        let repo_url = get_repo_url();
+
+        let key_file = key_file.canonicalize().expect("Failed to find key file");
+        let key = read_key_file(&key_file).unwrap_or_else(|e| panic!("{}", e));
+        verify_key(&repo_url, &key).await;
+
+        info!("Using key file {} instead of the keychain", key_file.display());
+
+        set_key_file(&repo_url, &key_file);
+        set_encrypted(&repo_url);
+    } else if args.key_provider || (read_provider_wrapped_key(&read_repo_config()).is_some() && key_provider().is_some()) {
+        let repo_url = get_repo_url();
+        let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+
+        let provider = key_provider().expect("No key command configured, set `command` under [key_provider] in config.toml");
+        let mut doc = read_repo_config();
+
+        let (key, wrapped) = match read_provider_wrapped_key(&doc) {
+            Some(wrapped) => {
+                info!("Unwrapping repo key with the key command");
+
+                let key = provider
+                    .unwrap(&format!("{owner}/{repo_name}"), &wrapped)
+                    .unwrap_or_else(|e| panic!("{}", e));
 
-        info!("Setting encrypted flag to true in config");
+                (key, None)
+            }
+            None => {
+                let key = load_derived_key(&owner, &repo_name).unwrap_or_else(|_| generate_data_key());
+                let wrapped = provider
+                    .wrap(&format!("{owner}/{repo_name}"), &key)
+                    .unwrap_or_else(|e| panic!("{}", e));
 
-        let mut doc = read_config();
-        doc[&format!("{owner}:{repo_name}")]["encrypted"] = value(true);
-        write_config(doc);
+                (key, Some(wrapped))
+            }
+        };
+
+        verify_key(&repo_url, &key).await;
+        save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain");
+
+        if let Some(wrapped) = wrapped {
+            write_provider_wrapped_key(&mut doc, &wrapped);
+            write_repo_config(doc);
+
+            info!("Repo key wrapped with the key command, commit .enva.toml to share it with your team");
+        }
+
+        set_encrypted(&repo_url);
+    } else if !read_recipients(&read_repo_config()).is_empty() {
+        info!("Unwrapping repo key from .enva.toml");
+
+        let repo_url = get_repo_url();
+        let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+
+        let key = unwrap_repo_key(&read_repo_config()).unwrap_or_else(|e| panic!("{}", e));
+        verify_key(&repo_url, &key).await;
+        save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain");
+
+        info!("Repo key saved successfully");
+
+        set_encrypted(&repo_url);
     }
-    
+
+    if args.seal {
+        if !is_encrypted(&get_repo_url()) {
+            panic!("Sealed snapshots require encryption, pass --password or add recipients first");
+        }
+
+        info!("Sealing snapshots, commit .enva.toml to share the setting with your team");
+
+        set_sealed();
+    }
+
+    if args.values {
+        if !is_encrypted(&get_repo_url()) {
+            panic!("Value-only encryption requires encryption, pass --password or add recipients first");
+        }
+
+        info!("Encrypting values only, commit .enva.toml to share the setting with your team");
+
+        set_values_mode();
+    }
+
     let enva_path = get_enva_executable_path().expect("Failed to get enva executable path");
 
     info!("Executing enva binary at: {}", enva_path.display());
@@ -77,33 +202,45 @@ pub async fn active(args: ActiveArgs) {
         commit_id,
         repo_url: get_repo_url(),
     }).await.expect("Failed to check commit").exists {
-        fetch().await;
+        fetch(FetchArgs::default()).await;
     }
 }
 
 pub async fn commit() {
+    let (commit_id, revision) = upload_snapshot().await;
+
+    info!("Commit pushed: {} (revision {})", commit_id, revision);
+}
+
+// Uploads the env files as a new revision of HEAD's snapshot, without needing a git commit
+pub async fn push() {
+    let (commit_id, revision) = upload_snapshot().await;
+
+    println!("Pushed revision {} of {}", revision, commit_id);
+}
+
+async fn upload_snapshot() -> (String, u32) {
     check_ownership().await;
 
     let repo = Repository::open(".").expect("Failed to open git repository");
 
     let repo_url = get_repo_url();
 
+    ensure_key(&repo_url).await;
+
     let head = repo.head().expect("Failed to get HEAD reference");
     let commit = head.peel_to_commit().expect("Failed to get commit from HEAD");
     let commit_id = commit.id().to_string();
 
     info!("Latest commit: {}", commit_id);
 
-    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
-
-    let doc = read_config();
+    let (env_files, archive) = encrypt_snapshot(&repo_url, &commit_id, read_env_file());
 
-    let encrypted = doc[&format!("{owner}:{repo_name}")]["encrypted"].as_bool().unwrap_or(false);
+    let key_check = is_encrypted(&repo_url).then(|| repo_key_check(&repo_url));
 
-    let env_files = match encrypted {
-        false => read_env_file(),
-        true => read_env_file().into_iter().map(|(k, v)| (k, encrypt_string(&repo_url, &v))).collect()
-    };
+    let parent = commit.parent_id(0).ok().map(|id| id.to_string());
+    let revision = latest_revision(&repo_url, &commit_id).await.map_or(1, |latest| latest + 1);
+    let manifest = sign_snapshot(&repo_url, &commit_id, revision, parent, None, &env_files, archive.as_deref());
 
     let res = endpoints::call_commit(CommitRequest {
         repo_url,
@@ -113,6 +250,10 @@ pub async fn commit() {
             .to_string(),
         commit_id: commit_id.clone(),
         env_files,
+        key_check,
+        manifest,
+        archive,
+        revision: Some(revision),
     })
     .await
     .expect("Failed to commit");
@@ -121,27 +262,56 @@ pub async fn commit() {
         panic!("Failed to commit: {}", res.error.unwrap_or_default());
     }
 
-    info!("Commit pushed: {}", commit_id);
+    (commit_id, res.revision.unwrap_or(revision))
 }
 
-pub async fn fetch() {
+pub async fn fetch(args: FetchArgs) {
     check_ownership().await;
 
     let repo = Repository::open(".").expect("Failed to open git repository");
 
     let repo_url = get_repo_url();
 
-    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Failed to parse GitHub repo URL");
+    ensure_key(&repo_url).await;
 
-    let head = repo.head().expect("Failed to get HEAD reference");
-    let commit = head.peel_to_commit().expect("Failed to get commit from HEAD");
-    let commit_id = commit.id().to_string();
+    let commit_id = match &args.branch {
+        Some(branch) => branch_head(&repo_url, branch).await,
+        None => resolve_commit(&repo, None),
+    };
 
     info!("Latest commit: {}", commit_id);
 
+    let decrypted = fetch_snapshot(&repo_url, &commit_id, args.revision).await.unwrap_or_else(|e| panic!("{}", e));
+
+    let policy = file_policy(&read_repo_config());
+
+    for (file_path, file) in decrypted {
+        write_env_file(&file_path, &file, &policy);
+    }
+}
+
+// Downloads, verifies and decrypts a snapshot in memory. Every file is decrypted before
+// any is returned, so a bad blob leaves the working tree untouched.
+async fn fetch_snapshot(repo_url: &str, commit_id: &str, revision: Option<u32>) -> Result<HashMap<String, EnvFile>, String> {
+    let (env_files, archive, legacy_blobs) = fetch_verified(repo_url, commit_id, revision).await?;
+
+    decrypt_snapshot(repo_url, commit_id, env_files, archive.as_deref(), &legacy_blobs)
+        .map_err(|e| format!("Failed to decrypt {}", e))
+}
+
+// Downloads a snapshot's blobs as stored and checks them against the signed manifest,
+// along with the files the server lists as pre-envelope blobs
+async fn fetch_verified(
+    repo_url: &str,
+    commit_id: &str,
+    revision: Option<u32>,
+) -> Result<(HashMap<String, EnvFile>, Option<Vec<u8>>, Vec<String>), String> {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Failed to parse GitHub repo URL");
+
     let res = endpoints::call_fetch(FetchRequest {
-        repo_url: repo_url.clone(),
-        commit_id,
+        repo_url: repo_url.to_string(),
+        commit_id: commit_id.to_string(),
+        revision,
     }).await.expect("Failed to fetch");
 
     if !res.success {
@@ -150,13 +320,1139 @@ pub async fn fetch() {
 
     info!("Env files fetched successfully");
 
-    let doc = read_config();
-    let encrypted = doc[&format!("{owner}:{repo_name}")]["encrypted"].as_bool().unwrap_or(false);
+    // Servers without revisions only hold the first one
+    let served = res.revision.unwrap_or(1);
+
+    if let Some(revision) = revision
+        && revision != served
+    {
+        return Err(format!("Refusing to use snapshot: asked for revision {} but the server returned revision {}", revision, served));
+    }
+
+    let env_files = res.env_files.unwrap_or_default();
 
-    for (file_path, content) in res.env_files.unwrap_or_default() {
-        std::fs::write(file_path, match encrypted {
-            false => content,
-            true => decrypt_string(&repo_url, &content)
-        }).expect("Failed to write env file");
+    match verify_snapshot(
+        &read_repo_config(),
+        &format!("{owner}/{repo_name}"),
+        commit_id,
+        served,
+        &env_files,
+        res.archive.as_deref(),
+        res.manifest.as_ref(),
+    ) {
+        Ok(Some(signer)) => info!("Snapshot signed by {}", signer),
+        Ok(None) => warn!("Snapshot is not signed"),
+        Err(e) => return Err(format!("Refusing to use snapshot: {}", e)),
+    }
+
+    Ok((env_files, res.archive, res.legacy_blobs))
+}
+
+// Latest revision of a commit's snapshot, None when it has none yet
+async fn latest_revision(repo_url: &str, commit_id: &str) -> Option<u32> {
+    let res = call_check(CheckCommitRequest {
+        repo_url: repo_url.to_string(),
+        commit_id: commit_id.to_string(),
+    })
+    .await
+    .expect("Failed to check commit");
+
+    if let Some(error) = res.error {
+        panic!("Failed to check commit: {}", error);
     }
-}
\ No newline at end of file
+
+    // Servers without revisions report an existing snapshot only
+    res.revision.or(res.exists.then_some(1))
+}
+
+const DEFAULT_RUN_FILES: [&str; 2] = [".env", ".env.local"];
+
+pub async fn run(args: RunArgs) {
+    let vars = load_vars(args.commit.as_deref(), args.file).await;
+
+    let status = Command::new(&args.command[0])
+        .args(&args.command[1..])
+        .envs(vars)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run {}: {}", args.command[0], e));
+
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+pub async fn export(args: ExportArgs) {
+    let vars = load_vars(args.commit.as_deref(), args.file).await;
+
+    let name = match args.name {
+        Some(name) => name,
+        None if args.format == ExportFormat::K8sSecret => {
+            let (_, repo_name) = enva_shared::parse_github_repo(&get_repo_url()).expect("Failed to parse GitHub repo URL");
+            secret_name(&repo_name).expect("Can't derive a Secret name from the repo name, pass one with --name")
+        }
+        None => String::new(),
+    };
+
+    match render(args.format, &vars, &name) {
+        Ok(output) => print!("{}", output),
+        Err(e) => panic!("Failed to export: {}", e),
+    }
+}
+
+// Merges the variables of another file or format into a local env file, then uploads a
+// snapshot for HEAD like the post-commit hook does
+pub async fn import(args: ImportArgs) {
+    check_synced_file(&args.file);
+
+    let stdin = args.source == Path::new("-");
+
+    let content = if stdin {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content).expect("Failed to read stdin");
+        content
+    } else {
+        std::fs::read_to_string(&args.source).unwrap_or_else(|e| panic!("Failed to read {}: {}", args.source.display(), e))
+    };
+
+    let format = args.format.unwrap_or_else(|| if stdin { ImportFormat::Dotenv } else { ImportFormat::detect(&args.source) });
+    let vars = parse(format, &content).unwrap_or_else(|e| panic!("Failed to import {}: {}", args.source.display(), e));
+
+    let mut doc = read_dotenv(&args.file);
+
+    for (key, value) in &vars {
+        doc.set(key, value);
+    }
+
+    write_dotenv(&args.file, &doc);
+    info!("Imported {} variables into {}", vars.len(), args.file);
+
+    push().await;
+}
+
+// Prints a variable as `enva run` would see it from the local files
+pub fn get(args: GetArgs) {
+    let files = if args.file.is_empty() {
+        DEFAULT_RUN_FILES.iter().map(|file| file.to_string()).collect()
+    } else {
+        args.file
+    };
+
+    let mut vars: HashMap<String, String> = HashMap::new();
+
+    for name in files {
+        let resolved = read_dotenv(&name).resolve(|var| vars.get(var).cloned().or_else(|| std::env::var(var).ok()));
+        vars.extend(resolved);
+    }
+
+    match vars.get(&args.key) {
+        Some(value) => println!("{}", value),
+        None => {
+            error!("{} is not set", args.key);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub async fn set(args: SetArgs) {
+    check_synced_file(&args.file);
+
+    let mut doc = read_dotenv(&args.file);
+
+    for assignment in &args.assignments {
+        let (key, value) = assignment
+            .split_once('=')
+            .unwrap_or_else(|| panic!("Expected KEY=VALUE, got {}", assignment));
+
+        if !is_valid_key(key) {
+            panic!("`{}` isn't a valid variable name", key);
+        }
+
+        doc.set(key, value);
+    }
+
+    write_dotenv(&args.file, &doc);
+
+    if args.push {
+        push().await;
+    }
+}
+
+pub async fn unset(args: UnsetArgs) {
+    check_synced_file(&args.file);
+
+    let mut doc = read_dotenv(&args.file);
+
+    for key in &args.keys {
+        if !doc.remove(key) {
+            warn!("{} is not set in {}", key, args.file);
+        }
+    }
+
+    write_dotenv(&args.file, &doc);
+
+    if args.push {
+        push().await;
+    }
+}
+
+// Fetches a commit's snapshot (HEAD by default) and resolves the variables of `files` in memory.
+// Later files override earlier ones, so `.env.local` wins over `.env` by default.
+async fn load_vars(commit: Option<&str>, files: Vec<String>) -> Vec<(String, String)> {
+    check_ownership().await;
+
+    let repo = Repository::open(".").expect("Failed to open git repository");
+
+    let repo_url = get_repo_url();
+
+    ensure_key(&repo_url).await;
+
+    let commit_id = resolve_commit(&repo, commit);
+
+    let mut snapshot = fetch_snapshot(&repo_url, &commit_id, None).await.unwrap_or_else(|e| panic!("{}", e));
+
+    let explicit = !files.is_empty();
+    let files = if explicit {
+        files
+    } else {
+        DEFAULT_RUN_FILES.iter().map(|file| file.to_string()).collect()
+    };
+
+    let mut vars: Vec<(String, String)> = Vec::new();
+
+    for name in files {
+        let Some(file) = snapshot.remove(&name) else {
+            if explicit {
+                panic!("{} is not in the snapshot of {}", name, commit_id);
+            }
+            continue;
+        };
+
+        let content = String::from_utf8(file.content).unwrap_or_else(|_| panic!("{} is not a UTF-8 dotenv file", name));
+        let doc = Dotenv::parse(&content).unwrap_or_else(|e| panic!("Failed to parse {}: {}", name, e));
+
+        // References resolve to files loaded earlier, then to the inherited environment
+        let loaded: HashMap<String, String> = vars.iter().cloned().collect();
+
+        for (key, value) in doc.resolve(|name| loaded.get(name).cloned().or_else(|| std::env::var(name).ok())) {
+            match vars.iter_mut().find(|(k, _)| *k == key) {
+                Some(var) => var.1 = value,
+                None => vars.push((key, value)),
+            }
+        }
+    }
+
+    vars
+}
+
+// Returns whether the repo still has blobs from before the envelope format
+async fn verify_key(repo_url: &str, key: &[u8]) -> bool {
+    let key_check = key_check(key);
+
+    let res = endpoints::call_key_check(KeyCheckRequest {
+        repo_url: repo_url.to_string(),
+        key_check: Some(key_check.clone()),
+    })
+    .await
+    .expect("Failed to verify key");
+
+    if !res.success {
+        panic!("Failed to verify key: {}", res.error.unwrap_or_default());
+    }
+
+    if res.key_check != Some(key_check) {
+        panic!("This key doesn't match the one your team uses, check the password");
+    }
+
+    set_key_generation(repo_url, res.key_generation);
+
+    info!("Key verified against the server");
+
+    res.legacy_blobs
+}
+
+// Follows the server's encryption policy for the repo, making sure the current key is in
+// the keychain and prompting for the password when it isn't
+async fn ensure_key(repo_url: &str) {
+    let res = endpoints::call_key_check(KeyCheckRequest {
+        repo_url: repo_url.to_string(),
+        key_check: None,
+    })
+    .await
+    .expect("Failed to get repo settings");
+
+    if !res.success {
+        panic!("Failed to get repo settings: {}", res.error.unwrap_or_default());
+    }
+
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    if !res.encrypted {
+        // Repos encrypted before the server tracked encryption were never registered, so it would
+        // keep accepting plaintext uploads and couldn't reject snapshots under another key
+        if is_encrypted(repo_url)
+            && let Ok(key) = load_derived_key(&owner, &repo_name)
+        {
+            info!("Registering this repo's key with the server");
+            verify_key(repo_url, &key).await;
+        }
+
+        return;
+    }
+
+    let local_key_check = load_derived_key(&owner, &repo_name).ok().map(|key| key_check(&key));
+
+    if local_key_check.is_none() || local_key_check != res.key_check {
+        info!("This repo is encrypted, but your key is missing or outdated");
+
+        let doc = read_repo_config();
+
+        let unwrapped = unwrap_provider_key(&doc, &owner, &repo_name)
+            .or_else(|| unwrap_repo_key(&doc).ok())
+            .filter(|key| res.key_check.as_ref() == Some(&key_check(key)));
+
+        match unwrapped {
+            Some(key) => save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain"),
+            None => {
+                let kdf = read_kdf(&doc)
+                    .expect("This repo is encrypted, but .enva.toml has no key parameters, pull the latest changes first");
+
+                let password = read_password(Some(None), false, &format!("Password for {owner}/{repo_name}: "), false)
+                    .expect("Failed to read password");
+                let key = derive_key(&kdf, &password);
+
+                if res.key_check.as_ref() != Some(&key_check(&key)) {
+                    panic!("This key doesn't match the one your team uses, check the password");
+                }
+
+                save_pwd(repo_url, &password, &kdf, key, res.legacy_blobs);
+            }
+        }
+
+        info!("Repo key saved to keychain");
+    }
+
+    set_encrypted(repo_url);
+    set_key_generation(repo_url, res.key_generation);
+}
+
+fn unwrap_provider_key(doc: &DocumentMut, owner: &str, repo_name: &str) -> Option<Vec<u8>> {
+    let wrapped = read_provider_wrapped_key(doc)?;
+    let provider = key_provider()?;
+
+    provider
+        .unwrap(&format!("{owner}/{repo_name}"), &wrapped)
+        .map_err(|e| warn!("Failed to unwrap repo key with the key command: {}", e))
+        .ok()
+}
+
+fn set_key_file(repo_url: &str, key_file: &Path) {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    let mut doc = read_config();
+    doc[&format!("{owner}:{repo_name}")]["key_file"] = value(key_file.to_string_lossy().as_ref());
+    write_config(doc);
+}
+
+fn set_encrypted(repo_url: &str) {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    info!("Setting encrypted flag to true in config");
+
+    let mut doc = read_config();
+    doc[&format!("{owner}:{repo_name}")]["encrypted"] = value(true);
+    write_config(doc);
+}
+
+pub fn key_generate(args: KeyGenerateArgs) {
+    let has_identity = load_identity().is_some();
+    let has_signing_key = load_keychain_signing_key().is_some();
+
+    if has_identity && has_signing_key && !args.force {
+        error!("Keys already exist. Use --force to replace them.");
+        return;
+    }
+
+    if !has_identity || args.force {
+        let public_key = generate_identity();
+
+        info!("Identity saved to keychain");
+
+        println!("{}", public_key);
+    }
+
+    if !has_signing_key || args.force {
+        let public_key = generate_signing_key();
+
+        info!("Signing key {} saved to keychain, share it with `enva key show --signing`", public_key);
+    }
+}
+
+pub fn key_show(args: KeyShowArgs) {
+    if args.signing {
+        let signing_key = load_signing_key().expect("No signing key found, run `enva key generate` first");
+
+        println!("{}", verifying_key(&signing_key));
+    } else {
+        let identity = load_identity().expect("No identity found, run `enva key generate` first");
+
+        println!("{}", public_key(&identity));
+    }
+}
+
+pub async fn key_split(args: KeySplitArgs) {
+    check_ownership().await;
+
+    if args.threshold < 2 || args.threshold > args.shares {
+        panic!("The threshold must be at least 2 and no more than the number of shares");
+    }
+
+    let repo_url = get_repo_url();
+    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+
+    let key = load_derived_key(&owner, &repo_name).expect("No repo key found, run `enva active` first");
+
+    // Shares of a stale key would be useless, so check it against the one the team uses
+    let res = endpoints::call_key_check(KeyCheckRequest {
+        repo_url: repo_url.clone(),
+        key_check: None,
+    })
+    .await
+    .expect("Failed to get key check");
+
+    if !res.success {
+        panic!("Failed to get key check: {}", res.error.unwrap_or_default());
+    }
+
+    if res.key_check != Some(key_check(&key)) {
+        panic!("Your repo key doesn't match the one your team uses, run `enva active` first");
+    }
+
+    info!("Give each share to a different admin, any {} of them can recover the repo key", args.threshold);
+
+    for share in split_key(&format!("{owner}/{repo_name}"), &key, args.shares, args.threshold) {
+        println!("{}", share);
+    }
+}
+
+pub async fn key_recover(args: KeyRecoverArgs) {
+    check_ownership().await;
+
+    let repo_url = get_repo_url();
+    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+    let repo = format!("{owner}/{repo_name}");
+
+    let mut shares = args.shares;
+
+    if shares.is_empty() {
+        let first = prompt_password("Share 1: ", false);
+        let threshold = share_threshold(&repo, &first).unwrap_or_else(|e| panic!("{}", e));
+
+        shares.push(first);
+
+        while shares.len() < threshold as usize {
+            shares.push(prompt_password(&format!("Share {}: ", shares.len() + 1), false));
+        }
+    }
+
+    let key = recover_key(&repo, &shares).unwrap_or_else(|e| panic!("{}", e));
+    verify_key(&repo_url, &key).await;
+
+    save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain");
+    set_encrypted(&repo_url);
+
+    info!("Repo key recovered, run `enva rekey` to set a new password");
+}
+
+pub async fn recipients_add(args: RecipientAddArgs) {
+    check_ownership().await;
+
+    let repo_url = get_repo_url();
+    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+
+    let mut new_recipients: Vec<(String, String)> = Vec::new();
+
+    if let (Some(name), Some(public_key)) = (args.name, args.public_key) {
+        new_recipients.push((name, public_key));
+    }
+
+    let token = get_token().expect("You need to login first");
+    let mut logins = args.github;
+
+    for team in args.team {
+        let (org, team_slug) = team.split_once('/').expect("Teams must be given as org/team");
+
+        logins.extend(
+            enva_shared::fetch_team_members(&token, org, team_slug)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to list members of {}: {}", team, e)),
+        );
+    }
+
+    for login in logins {
+        let keys: Vec<String> = enva_shared::fetch_ssh_keys(&token, &login)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to fetch SSH keys for {}: {}", login, e))
+            .iter()
+            .filter_map(|key| ssh_public_key_to_recipient(key))
+            .collect();
+
+        if keys.is_empty() {
+            warn!("GitHub user {} has no ed25519 SSH keys, skipping", login);
+        }
+
+        new_recipients.extend(keys.into_iter().map(|key| (login.clone(), key)));
+    }
+
+    let mut doc = read_repo_config();
+    let mut recipients = read_recipients(&doc);
+
+    let data_key = match load_derived_key(&owner, &repo_name) {
+        Ok(key) => key,
+        Err(_) if recipients.is_empty() => {
+            info!("No repo key found, generating a new one");
+
+            let identity = load_identities()
+                .into_iter()
+                .next()
+                .expect("No identity found, run `enva key generate` or add an ed25519 SSH key");
+            let own_public_key = public_key(&identity);
+
+            let key = generate_data_key();
+            verify_key(&repo_url, &key).await;
+            save_derived_key(&owner, &repo_name, key.clone()).expect("Failed to save key to keychain");
+            set_encrypted(&repo_url);
+
+            recipients.push(Recipient {
+                name: git_user_name().unwrap_or_else(|| "owner".to_string()),
+                wrapped_key: wrap_key(&key, &own_public_key).expect("Failed to wrap repo key"),
+                public_key: own_public_key,
+            });
+
+            key
+        }
+        Err(_) => panic!("No repo key found, run `enva active` first"),
+    };
+
+    let names: Vec<String> = new_recipients.iter().map(|(name, _)| name.clone()).collect();
+
+    // A GitHub user may have several keys, so replace all of a name's entries at once
+    recipients.retain(|r| !names.contains(&r.name));
+
+    for (name, public_key) in new_recipients {
+        recipients.retain(|r| r.public_key != public_key);
+        recipients.push(Recipient {
+            name: name.clone(),
+            wrapped_key: wrap_key(&data_key, &public_key).expect("Failed to wrap repo key"),
+            public_key,
+        });
+
+        info!("Recipient {} added", name);
+    }
+
+    write_recipients(&mut doc, &recipients);
+    write_repo_config(doc);
+
+    info!("Commit .enva.toml to share the recipients with your team");
+}
+
+pub async fn recipients_remove(args: RecipientRemoveArgs) {
+    check_ownership().await;
+
+    let repo_url = get_repo_url();
+    let mut recipients = read_recipients(&read_repo_config());
+
+    let count = recipients.len();
+    recipients.retain(|r| r.name != args.name);
+
+    if recipients.len() == count {
+        error!("Recipient {} not found", args.name);
+        return;
+    }
+
+    if recipients.is_empty() {
+        panic!("{} is the last recipient, removing them would leave nobody able to unwrap the repo key", args.name);
+    }
+
+    // The removed recipient may have kept the current key, so dropping their wrapped copy isn't enough.
+    // The rekey writes the remaining recipients, and leaves .enva.toml untouched if it fails
+    check_rekey(&repo_url);
+    rekey_repo(repo_url, NewKey::Recipients(recipients)).await;
+
+    warn!(
+        "Recipient {} removed and the repo rekeyed. Values they already fetched stay known to them, rotate any secret they could see",
+        args.name
+    );
+}
+
+pub fn recipients_list() {
+    for recipient in read_recipients(&read_repo_config()) {
+        println!("{}\t{}", recipient.name, recipient.public_key);
+    }
+}
+
+fn git_user_name() -> Option<String> {
+    let repo = Repository::open(".").ok()?;
+    repo.config().ok()?.get_string("user.name").ok()
+}
+
+// Where the key a rekey switches to comes from
+enum NewKey {
+    Password { password: String, kdf: Kdf },
+    // A fresh random data key, wrapped for each of these recipients
+    Recipients(Vec<Recipient>),
+    // A fresh random data key, wrapped only by the key command
+    Provider,
+}
+
+pub async fn rekey(args: RekeyArgs) {
+    check_ownership().await;
+
+    let repo_url = get_repo_url();
+    check_rekey(&repo_url);
+
+    let doc = read_repo_config();
+    let recipients = read_recipients(&doc);
+    // Without key parameters the repo has no password, its key is only reachable through the key command
+    let provider_only = read_provider_wrapped_key(&doc).is_some() && read_kdf(&doc).is_none();
+
+    if (!recipients.is_empty() || provider_only) && (args.password.is_some() || args.password_stdin || args.kdf.is_some()) {
+        panic!("This repo has no password, `enva rekey` generates a new random key and takes no password or KDF");
+    }
+
+    let new_key = if !recipients.is_empty() {
+        NewKey::Recipients(recipients)
+    } else if provider_only {
+        NewKey::Provider
+    } else {
+        let password = read_password(args.password.or(Some(None)), args.password_stdin, "New repo password: ", true)
+            .expect("A new password is required");
+
+        let kind = args
+            .kdf
+            .or_else(|| read_kdf(&read_repo_config()).and_then(|kdf| kdf.kind()))
+            .unwrap_or(KdfKind::Pbkdf2);
+
+        NewKey::Password {
+            password,
+            kdf: Kdf::new(kind, args.memory_cost, args.time_cost),
+        }
+    };
+
+    rekey_repo(repo_url, new_key).await;
+}
+
+// A rekey needs the current key in this machine's key store, which it replaces
+fn check_rekey(repo_url: &str) {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    if !is_encrypted(repo_url) {
+        panic!("This repo is not encrypted, run `enva active --password` first");
+    }
+
+    if key_file(&owner, &repo_name).is_some() {
+        panic!("This machine uses a key file, run `enva rekey` from a machine that keeps the key in its keychain");
+    }
+
+    if env::var(KEY_ENV).is_ok() {
+        panic!("{} is set, unset it and run `enva rekey` from a machine that keeps the key in its keychain", KEY_ENV);
+    }
+
+    // The new key has to be wrapped again, or .enva.toml would keep handing out the old one
+    if read_provider_wrapped_key(&read_repo_config()).is_some() && key_provider().is_none() {
+        panic!("This repo's key is wrapped by a key command, set `command` under [key_provider] in config.toml to rekey it");
+    }
+}
+
+// Re-encrypts every stored snapshot under a new key in a single server-side write,
+// restoring the local key and .enva.toml if the server rejects it
+async fn rekey_repo(repo_url: String, new_key: NewKey) {
+    let (owner, repo_name) = enva_shared::parse_github_repo(&repo_url).expect("Invalid repo URL");
+
+    let res = endpoints::call_key_check(KeyCheckRequest {
+        repo_url: repo_url.clone(),
+        key_check: None,
+    })
+    .await
+    .expect("Failed to get key check");
+
+    if !res.success {
+        panic!("Failed to get key check: {}", res.error.unwrap_or_default());
+    }
+
+    if res.key_check.as_ref().is_some_and(|key_check| *key_check != repo_key_check(&repo_url)) {
+        panic!("Your key doesn't match the one your team uses, run `enva active --password` with the current password first");
+    }
+
+    let old_key_check = res.key_check;
+    let old_generation = res.key_generation;
+    set_key_generation(&repo_url, old_generation);
+
+    let res = endpoints::call_list(ListRequest {
+        repo_url: repo_url.clone(),
+    })
+    .await
+    .expect("Failed to list snapshots");
+
+    if !res.success {
+        panic!("Failed to list snapshots: {}", res.error.unwrap_or_default());
+    }
+
+    // Decrypt everything with the current key before touching it
+    let mut snapshots = Vec::new();
+
+    for commit in res.commits.unwrap_or_default() {
+        // Servers without revisions list none, their single snapshot is revision 1
+        let revisions = if commit.revisions.is_empty() {
+            vec![(1, None)]
+        } else {
+            commit.revisions.iter().map(|revision| (revision.revision, revision.branch.clone())).collect()
+        };
+
+        for (revision, branch) in revisions {
+            let res = endpoints::call_fetch(FetchRequest {
+                repo_url: repo_url.clone(),
+                commit_id: commit.commit_id.clone(),
+                revision: Some(revision),
+            })
+            .await
+            .expect("Failed to fetch");
+
+            if !res.success {
+                panic!("Failed to fetch {}: {}", commit.commit_id, res.error.unwrap_or_default());
+            }
+
+            // Each revision keeps the parent and author it was signed with; the re-encrypted copy is signed by us
+            let manifest = res
+                .manifest
+                .as_ref()
+                .and_then(|m| serde_json::from_str::<Manifest>(&m.manifest).ok());
+            let parent = manifest.as_ref().and_then(|m| m.parent.clone());
+            let author = manifest.map(|m| m.author);
+
+            let env_files = res.env_files.unwrap_or_default();
+
+            // Revisions stored before the repo was encrypted hold plaintext, which gets encrypted now
+            let plaintext = res.archive.is_none()
+                && !env_files.is_empty()
+                && env_files
+                    .iter()
+                    .all(|(name, file)| !res.legacy_blobs.contains(name) && !is_encrypted_blob(&file.content));
+
+            let env_files = if plaintext {
+                info!("Encrypting {} revision {}, stored before the repo was encrypted", commit.commit_id, revision);
+                env_files
+            } else {
+                decrypt_snapshot(&repo_url, &commit.commit_id, env_files, res.archive.as_deref(), &res.legacy_blobs)
+                    .unwrap_or_else(|e| panic!("Failed to decrypt {} in {} revision {}", e, commit.commit_id, revision))
+            };
+
+            let branch = branch.unwrap_or_else(|| commit.branch.clone());
+
+            snapshots.push((branch, commit.commit_id.clone(), revision, parent, author, env_files));
+        }
+    }
+
+    // Kept to roll back the local key if the server rejects the rekey
+    let old_repo_config = read_repo_config();
+    let old_key = load_derived_key(&owner, &repo_name).expect("Failed to load key from keychain");
+    let old_legacy_key = load_derived_key(&owner, &format!("{repo_name}:legacy")).ok();
+
+    let new_generation = old_generation + 1;
+    let password_mode = matches!(new_key, NewKey::Password { .. });
+
+    let key = match &new_key {
+        NewKey::Password { password, kdf } => derive_key(kdf, password),
+        NewKey::Recipients(_) | NewKey::Provider => generate_data_key(),
+    };
+    let new_key_check = key_check(&key);
+
+    // Everything that can fail is wrapped before any local state changes, and the config is
+    // restored below if the server rejects the rekey, so .enva.toml never holds a key the server doesn't use
+    let provider_wrapped = read_provider_wrapped_key(&old_repo_config).map(|_| {
+        key_provider()
+            .expect("No key command configured")
+            .wrap(&format!("{owner}/{repo_name}"), &key)
+            .unwrap_or_else(|e| panic!("Failed to wrap the new key with the key command: {}", e))
+    });
+
+    // Every recipient gets the new key in the same step, so no stale wrapped key is left behind
+    let recipients = match &new_key {
+        NewKey::Recipients(recipients) => Some(
+            recipients
+                .iter()
+                .map(|recipient| Recipient {
+                    name: recipient.name.clone(),
+                    public_key: recipient.public_key.clone(),
+                    wrapped_key: wrap_key(&key, &recipient.public_key)
+                        .unwrap_or_else(|e| panic!("Failed to wrap the new key for {}: {}", recipient.name, e)),
+                })
+                .collect::<Vec<_>>(),
+        ),
+        _ => None,
+    };
+
+    match new_key {
+        // Rekeyed blobs all use the envelope, so the legacy key is dropped
+        NewKey::Password { password, kdf } => save_pwd(&repo_url, &password, &kdf, key, false),
+        NewKey::Recipients(_) | NewKey::Provider => {
+            save_derived_key(&owner, &repo_name, key).expect("Failed to save key to keychain");
+            keystore::delete(&format!("{owner}:{repo_name}:legacy")).expect("Failed to remove legacy key from keychain");
+        }
+    }
+
+    if recipients.is_some() || provider_wrapped.is_some() {
+        let mut doc = read_repo_config();
+
+        if let Some(recipients) = &recipients {
+            write_recipients(&mut doc, recipients);
+        }
+
+        if let Some(wrapped) = &provider_wrapped {
+            write_provider_wrapped_key(&mut doc, wrapped);
+        }
+
+        write_repo_config(doc);
+    }
+
+    set_key_generation(&repo_url, new_generation);
+
+    info!("Re-encrypting {} snapshots under key generation {}", snapshots.len(), new_generation);
+
+    let snapshots = snapshots
+        .into_iter()
+        .map(|(branch, commit_id, revision, parent, author, env_files)| {
+            let (env_files, archive) = encrypt_snapshot(&repo_url, &commit_id, env_files);
+            let manifest =
+                sign_snapshot(&repo_url, &commit_id, revision, parent, author, &env_files, archive.as_deref());
+
+            RekeySnapshot {
+                branch,
+                commit_id,
+                revision,
+                env_files,
+                manifest,
+                archive,
+            }
+        })
+        .collect();
+
+    let res = endpoints::call_rekey(RekeyRequest {
+        repo_url: repo_url.clone(),
+        old_key_check,
+        key_check: new_key_check,
+        key_generation: new_generation,
+        snapshots,
+    })
+    .await;
+
+    let error = match res {
+        Some(res) if res.success => None,
+        Some(res) => Some(res.error.unwrap_or_default()),
+        None => Some("no response from server".to_string()),
+    };
+
+    if let Some(error) = error {
+        write_repo_config(old_repo_config);
+        save_derived_key(&owner, &repo_name, old_key).expect("Failed to restore key to keychain");
+
+        if let Some(legacy_key) = old_legacy_key {
+            save_derived_key(&owner, &format!("{repo_name}:legacy"), legacy_key)
+                .expect("Failed to restore key to keychain");
+        }
+
+        set_key_generation(&repo_url, old_generation);
+
+        panic!("Rekey failed, nothing was changed: {}", error);
+    }
+
+    if password_mode {
+        info!("Rekey complete, commit .enva.toml and ask your team to run `enva active --password` again");
+    } else {
+        info!("Rekey complete, commit .enva.toml and ask your team to run `enva active` again");
+    }
+}
+
+fn sign_snapshot(
+    repo_url: &str,
+    commit_id: &str,
+    revision: u32,
+    parent: Option<String>,
+    author: Option<String>,
+    env_files: &HashMap<String, EnvFile>,
+    archive: Option<&[u8]>,
+) -> Option<SignedManifest> {
+    let Some(signing_key) = load_signing_key() else {
+        warn!("No signing key found, the snapshot won't be signed. Run `enva key generate` to create one.");
+        return None;
+    };
+
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+    // Re-signed snapshots keep their original author
+    let author = author.or_else(git_user_name).unwrap_or_else(|| verifying_key(&signing_key));
+
+    let manifest = build_manifest(&format!("{owner}/{repo_name}"), commit_id, revision, parent, &author, env_files, archive);
+
+    Some(sign(&manifest, &signing_key))
+}
+
+pub async fn signers_add(args: SignerAddArgs) {
+    let mut new_signers: Vec<(String, String)> = Vec::new();
+
+    if let (Some(name), Some(public_key)) = (args.name, args.public_key) {
+        new_signers.push((name, public_key));
+    }
+
+    if !args.github.is_empty() {
+        let token = get_token().expect("You need to login first");
+
+        for login in args.github {
+            let keys: Vec<String> = enva_shared::fetch_ssh_keys(&token, &login)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to fetch SSH keys for {}: {}", login, e))
+                .iter()
+                .filter_map(|key| ssh_public_key_to_signer(key))
+                .collect();
+
+            if keys.is_empty() {
+                warn!("GitHub user {} has no ed25519 SSH keys, skipping", login);
+            }
+
+            new_signers.extend(keys.into_iter().map(|key| (login.clone(), key)));
+        }
+    }
+
+    let mut doc = read_repo_config();
+    let mut signers = read_signers(&doc);
+
+    let names: Vec<String> = new_signers.iter().map(|(name, _)| name.clone()).collect();
+    signers.retain(|s| !names.contains(&s.name));
+
+    for (name, public_key) in new_signers {
+        signers.retain(|s| s.public_key != public_key);
+        signers.push(Signer { name: name.clone(), public_key });
+
+        info!("Signer {} trusted", name);
+    }
+
+    write_signers(&mut doc, &signers);
+    write_repo_config(doc);
+
+    info!("Commit .enva.toml to share the trusted signers with your team");
+}
+
+pub fn signers_remove(args: SignerRemoveArgs) {
+    let mut doc = read_repo_config();
+    let mut signers = read_signers(&doc);
+
+    let count = signers.len();
+    signers.retain(|s| s.name != args.name);
+
+    if signers.len() == count {
+        error!("Signer {} not found", args.name);
+        return;
+    }
+
+    write_signers(&mut doc, &signers);
+    write_repo_config(doc);
+
+    info!("Signer {} removed", args.name);
+}
+
+pub fn signers_list() {
+    for signer in read_signers(&read_repo_config()) {
+        println!("{}\t{}", signer.name, signer.public_key);
+    }
+}
+
+fn resolve_commit(repo: &Repository, commit: Option<&str>) -> String {
+    repo.revparse_single(commit.unwrap_or("HEAD"))
+        .and_then(|object| object.peel_to_commit())
+        .expect("Failed to resolve commit")
+        .id()
+        .to_string()
+}
+
+async fn list_branches(repo_url: &str) -> Vec<BranchInfo> {
+    let res = endpoints::call_branches(BranchesRequest {
+        repo_url: repo_url.to_string(),
+    })
+    .await
+    .expect("Failed to list branches");
+
+    if !res.success {
+        panic!("Failed to list branches: {}", res.error.unwrap_or_default());
+    }
+
+    res.branches.unwrap_or_default()
+}
+
+// Commit of the latest snapshot uploaded from `branch`, which needn't exist locally
+async fn branch_head(repo_url: &str, branch: &str) -> String {
+    list_branches(repo_url)
+        .await
+        .into_iter()
+        .find(|head| head.branch == branch)
+        .map(|head| head.commit_id)
+        .unwrap_or_else(|| panic!("No snapshots were uploaded from branch {}", branch))
+}
+
+// Lists the branches with snapshots and the commit of their latest upload
+pub async fn branches() {
+    check_ownership().await;
+
+    let repo = Repository::open(".").expect("Failed to open git repository");
+    let current = repo.head().ok().and_then(|head| head.shorthand().map(str::to_string));
+
+    for head in list_branches(&get_repo_url()).await {
+        println!(
+            "{} {} {} {}",
+            if current.as_deref() == Some(head.branch.as_str()) { "*" } else { " " },
+            head.branch,
+            &head.commit_id[..7.min(head.commit_id.len())],
+            head.updated_at
+                .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                .unwrap_or_else(|| "unknown date".to_string()),
+        );
+    }
+}
+
+// Lists the stored revisions of a commit's snapshot, HEAD by default
+pub async fn history(args: HistoryArgs) {
+    check_ownership().await;
+
+    let repo = Repository::open(".").expect("Failed to open git repository");
+    let commit_id = resolve_commit(&repo, args.commit.as_deref());
+
+    let res = endpoints::call_list(ListRequest {
+        repo_url: get_repo_url(),
+    })
+    .await
+    .expect("Failed to list snapshots");
+
+    if !res.success {
+        panic!("Failed to list snapshots: {}", res.error.unwrap_or_default());
+    }
+
+    let commit = res
+        .commits
+        .unwrap_or_default()
+        .into_iter()
+        .find(|commit| commit.commit_id == commit_id)
+        .unwrap_or_else(|| panic!("No snapshot stored for {}", commit_id));
+
+    let latest = commit.revisions.last().map(|revision| revision.revision);
+
+    for revision in commit.revisions.iter().rev() {
+        println!(
+            "{:>3} {} {}{}",
+            revision.revision,
+            revision
+                .uploaded_at
+                .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                .unwrap_or_else(|| "unknown date".to_string()),
+            revision.uploader.as_deref().unwrap_or("unknown uploader"),
+            if Some(revision.revision) == latest { " (latest)" } else { "" },
+        );
+    }
+}
+
+// Restores an earlier revision as a new one and, for HEAD, writes it to the working tree
+pub async fn rollback(args: RollbackArgs) {
+    check_ownership().await;
+
+    let repo = Repository::open(".").expect("Failed to open git repository");
+    let commit_id = resolve_commit(&repo, args.commit.as_deref());
+    let head_id = resolve_commit(&repo, None);
+
+    let repo_url = get_repo_url();
+
+    // The copy is signed for the revision it becomes, after checking the one it restores
+    let (env_files, archive, _) = fetch_verified(&repo_url, &commit_id, Some(args.revision))
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    let new_revision = latest_revision(&repo_url, &commit_id).await.map_or(1, |latest| latest + 1);
+    let parent = repo
+        .find_commit(git2::Oid::from_str(&commit_id).expect("Invalid commit id"))
+        .ok()
+        .and_then(|commit| commit.parent_id(0).ok())
+        .map(|id| id.to_string());
+    let manifest = sign_snapshot(&repo_url, &commit_id, new_revision, parent, None, &env_files, archive.as_deref());
+
+    let res = endpoints::call_rollback(RollbackRequest {
+        repo_url,
+        commit_id: commit_id.clone(),
+        revision: args.revision,
+        manifest,
+        new_revision: Some(new_revision),
+    })
+    .await
+    .expect("Failed to roll back");
+
+    if !res.success {
+        panic!("Failed to roll back: {}", res.error.unwrap_or_default());
+    }
+
+    println!(
+        "Restored revision {} of {} as revision {}",
+        args.revision,
+        commit_id,
+        res.revision.unwrap_or_default()
+    );
+
+    if commit_id == head_id {
+        fetch(FetchArgs::default()).await;
+    }
+}
+
+pub async fn log() {
+    check_ownership().await;
+
+    let repo_url = get_repo_url();
+
+    let res = endpoints::call_list(ListRequest {
+        repo_url: repo_url.clone(),
+    })
+    .await
+    .expect("Failed to list snapshots");
+
+    if !res.success {
+        panic!("Failed to list snapshots: {}", res.error.unwrap_or_default());
+    }
+
+    let mut snapshots: HashMap<String, CommitInfo> = res
+        .commits
+        .unwrap_or_default()
+        .into_iter()
+        .map(|c| (c.commit_id.clone(), c))
+        .collect();
+
+    let signers = read_signers(&read_repo_config());
+
+    let repo = Repository::open(".").expect("Failed to open git repository");
+    let mut revwalk = repo.revwalk().expect("Failed to walk git history");
+    revwalk.push_head().expect("Failed to get HEAD reference");
+
+    for oid in revwalk.flatten() {
+        let Some(snapshot) = snapshots.remove(&oid.to_string()) else {
+            continue;
+        };
+
+        let summary = repo
+            .find_commit(oid)
+            .ok()
+            .and_then(|c| c.summary().map(|s| s.to_string()))
+            .unwrap_or_default();
+
+        let signer = match &snapshot.manifest {
+            None => "unsigned".to_string(),
+            Some(signed) => match verify_signature(signed) {
+                Err(e) => e,
+                Ok(manifest) => match signers.iter().find(|s| s.public_key == signed.signer) {
+                    Some(signer) => format!("signed by {}", signer.name),
+                    None => format!("signed by {} (untrusted key)", manifest.author),
+                },
+            },
+        };
+
+        println!("{} {} [{}] {}", &snapshot.commit_id[..7.min(snapshot.commit_id.len())], snapshot.branch, signer, summary);
+    }
+}
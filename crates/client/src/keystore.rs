@@ -0,0 +1,242 @@
+use aes_gcm::{
+    AeadCore, Aes256Gcm,
+    aead::{Aead, KeyInit, OsRng, Payload, rand_core::RngCore},
+};
+use argon2::Argon2;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use crate::encryption::SERVICE;
+use crate::passphrase::prompt_password;
+use crate::utils::{get_config_path, read_config};
+use hkdf::Hkdf;
+use keyring::Entry;
+use sha2::Sha256;
+use std::env;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use toml_edit::{DocumentMut, value};
+
+const KEYSTORE_FILE: &str = "keystore.toml";
+const PASSPHRASE_ENV: &str = "ENVA_KEYSTORE_PASSPHRASE";
+const MACHINE_ID_PATHS: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const MACHINE_KEY_INFO: &[u8] = b"photon-hq/enva keystore";
+
+// Unlocked once per process, so a passphrase is asked for at most once
+static FILE_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+// Selected with `[keystore] backend = "file"` in config.toml, for machines without a keychain
+enum Backend {
+    Keychain,
+    File,
+}
+
+fn backend() -> Backend {
+    match keystore_setting("backend").as_deref() {
+        Some("file") => Backend::File,
+        _ => Backend::Keychain,
+    }
+}
+
+fn keystore_setting(name: &str) -> Option<String> {
+    read_config()
+        .get("keystore")
+        .and_then(|keystore| keystore.get(name))
+        .and_then(|setting| setting.as_str())
+        .map(str::to_string)
+}
+
+pub fn get(account: &str) -> Result<String, String> {
+    match backend() {
+        Backend::Keychain => Entry::new(SERVICE, account)
+            .and_then(|entry| entry.get_password())
+            .map_err(|e| e.to_string()),
+        Backend::File => file_get(account),
+    }
+}
+
+pub fn set(account: &str, secret: &str) -> Result<(), String> {
+    match backend() {
+        Backend::Keychain => Entry::new(SERVICE, account)
+            .and_then(|entry| entry.set_password(secret))
+            .map_err(|e| e.to_string()),
+        Backend::File => file_set(account, secret),
+    }
+}
+
+// Removing an entry that doesn't exist is not an error
+pub fn delete(account: &str) -> Result<(), String> {
+    match backend() {
+        Backend::Keychain => match Entry::new(SERVICE, account).and_then(|entry| entry.delete_credential()) {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        },
+        Backend::File => file_delete(account),
+    }
+}
+
+fn file_get(account: &str) -> Result<String, String> {
+    let mut doc = read_keystore();
+
+    let data = doc
+        .get("entries")
+        .and_then(|entries| entries.get(account))
+        .and_then(|entry| entry.as_str())
+        .and_then(|entry| STANDARD.decode(entry).ok())
+        .filter(|data| data.len() >= 12)
+        .ok_or_else(|| format!("No entry for {} in the keystore", account))?;
+
+    let (nonce, ciphertext) = data.split_at(12);
+    let cipher = Aes256Gcm::new_from_slice(&file_key(&mut doc)).expect("Invalid keystore key length");
+
+    let secret = cipher
+        .decrypt(nonce.into(), Payload { msg: ciphertext, aad: account.as_bytes() })
+        .map_err(|_| "Failed to unlock the keystore, check the passphrase".to_string())?;
+
+    String::from_utf8(secret).map_err(|e| e.to_string())
+}
+
+fn file_set(account: &str, secret: &str) -> Result<(), String> {
+    let _lock = lock_keystore()?;
+    let mut doc = read_keystore();
+
+    let cipher = Aes256Gcm::new_from_slice(&file_key(&mut doc)).expect("Invalid keystore key length");
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: secret.as_bytes(), aad: account.as_bytes() })
+        .map_err(|e| e.to_string())?;
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&ciphertext);
+
+    if doc.get("entries").is_none() {
+        doc["entries"] = toml_edit::table();
+    }
+
+    doc["entries"][account] = value(STANDARD.encode(data));
+    write_keystore(&doc)
+}
+
+fn file_delete(account: &str) -> Result<(), String> {
+    let _lock = lock_keystore()?;
+    let mut doc = read_keystore();
+
+    let removed = doc
+        .get_mut("entries")
+        .and_then(|entries| entries.as_table_like_mut())
+        .and_then(|entries| entries.remove(account))
+        .is_some();
+
+    if removed {
+        write_keystore(&doc)?;
+    }
+
+    Ok(())
+}
+
+// With `protection = "passphrase"` the file key comes from ENVA_KEYSTORE_PASSPHRASE or a prompt.
+// Otherwise it is derived from the machine id, which only keeps a copied file from being usable elsewhere.
+fn file_key(doc: &mut DocumentMut) -> [u8; 32] {
+    if doc.get("salt").is_none() {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        doc["salt"] = value(STANDARD.encode(salt));
+    }
+
+    let salt = STANDARD
+        .decode(doc.get("salt").and_then(|salt| salt.as_str()).unwrap_or_default())
+        .expect("Invalid salt in keystore");
+
+    *FILE_KEY.get_or_init(|| {
+        let mut key = [0u8; 32];
+
+        match keystore_setting("protection").as_deref() {
+            Some("passphrase") => {
+                let passphrase = env::var(PASSPHRASE_ENV)
+                    .unwrap_or_else(|_| prompt_password("Keystore passphrase: ", false));
+
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                    .expect("Failed to derive keystore key");
+            }
+            _ => {
+                let machine_id = MACHINE_ID_PATHS
+                    .iter()
+                    .find_map(|path| std::fs::read_to_string(path).ok())
+                    .expect("No machine id found, set `protection = \"passphrase\"` under [keystore] in config.toml");
+
+                Hkdf::<Sha256>::new(Some(&salt), machine_id.trim().as_bytes())
+                    .expand(MACHINE_KEY_INFO, &mut key)
+                    .expect("Failed to derive keystore key");
+            }
+        }
+
+        key
+    })
+}
+
+fn keystore_path() -> PathBuf {
+    get_config_path()
+        .expect("Failed to get config path")
+        .with_file_name(KEYSTORE_FILE)
+}
+
+fn read_keystore() -> DocumentMut {
+    let text = std::fs::read_to_string(keystore_path()).unwrap_or_else(|_| String::new());
+    text.parse::<DocumentMut>().unwrap_or_default()
+}
+
+// Held around every read-modify-write, so two hooks saving keys at once can't drop each other's entries.
+// Released when the returned file is dropped.
+fn lock_keystore() -> Result<std::fs::File, String> {
+    let path = keystore_path().with_extension("toml.lock");
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| e.to_string())?;
+
+    file.lock().map_err(|e| format!("Failed to lock the keystore: {}", e))?;
+
+    Ok(file)
+}
+
+// Written to a temporary file and renamed, so a crash never leaves a half-written keystore
+fn write_keystore(doc: &DocumentMut) -> Result<(), String> {
+    let path = keystore_path();
+    let tmp_path = path.with_extension("toml.tmp");
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    // A leftover from an interrupted write may have other permissions, so it is never reused
+    match std::fs::remove_file(&tmp_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.to_string()),
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // Created owner-only, so the entries are never readable by others even briefly
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path).map_err(|e| e.to_string())?;
+    file.write_all(doc.to_string().as_bytes()).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+
+    std::fs::rename(&tmp_path, &path).map_err(|e| e.to_string())
+}
@@ -1,9 +1,18 @@
 mod encryption;
 mod endpoints;
+mod formats;
 mod handlers;
+mod keystore;
+mod manifest;
+mod passphrase;
+mod recipients;
+mod shares;
 mod utils;
 
 use clap::{Args, Parser, Subcommand};
+use encryption::{ARGON2_MEMORY_COST, ARGON2_TIME_COST, KdfKind};
+use formats::{ExportFormat, ImportFormat};
+use std::path::PathBuf;
 #[derive(Parser, Debug)]
 #[command(
     name = "enva",
@@ -20,7 +29,34 @@ enum Command {
     Login(LoginArgs),
     Active(ActiveArgs),
     Commit,
-    Fetch,
+    #[command(about = "Upload the env files as a new revision of HEAD's snapshot, without a git commit")]
+    Push,
+    Fetch(FetchArgs),
+    Key(KeyArgs),
+    Recipients(RecipientsArgs),
+    #[command(about = "Re-encrypt every snapshot of the repo under a new password or key parameters")]
+    Rekey(RekeyArgs),
+    Signers(SignersArgs),
+    #[command(about = "List the snapshots of the current branch with their signers")]
+    Log,
+    #[command(about = "List the stored revisions of a commit's snapshot")]
+    History(HistoryArgs),
+    #[command(about = "List the branches with snapshots and their latest commit")]
+    Branches,
+    #[command(about = "Restore an earlier revision of a commit's snapshot as its latest")]
+    Rollback(RollbackArgs),
+    #[command(about = "Run a command with the snapshot's variables in its environment, without writing env files")]
+    Run(RunArgs),
+    #[command(about = "Print the snapshot's variables in another format")]
+    Export(ExportArgs),
+    #[command(about = "Merge variables from a JSON, YAML, Kubernetes Secret or dotenv file and upload a snapshot for HEAD")]
+    Import(ImportArgs),
+    #[command(about = "Print a variable from the local env files")]
+    Get(GetArgs),
+    #[command(about = "Set variables in a local env file, keeping its formatting")]
+    Set(SetArgs),
+    #[command(about = "Remove variables from a local env file")]
+    Unset(UnsetArgs),
 }
 
 #[derive(Args, Debug)]
@@ -35,8 +71,270 @@ struct LoginArgs {
 
 #[derive(Args, Debug)]
 struct ActiveArgs {
-    #[arg(long, short, help = "Set password for encryption")]
-    password: Option<String>,
+    #[arg(long, short, num_args = 0..=1, help = "Enable encryption, prompting for the password (passing it inline is deprecated)")]
+    password: Option<Option<String>>,
+
+    #[arg(long, conflicts_with = "password", help = "Read the password from stdin")]
+    password_stdin: bool,
+
+    #[arg(long, conflicts_with_all = ["password", "password_stdin"], help = "Use a raw 32-byte key file instead of the keychain, for CI machines")]
+    key_file: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["password", "password_stdin", "key_file"], help = "Wrap the repo key with the key command set under [key_provider] in config.toml")]
+    key_provider: bool,
+
+    #[arg(long, value_enum, help = "Key derivation function for a newly encrypted repo")]
+    kdf: Option<KdfKind>,
+
+    #[arg(long, default_value_t = ARGON2_MEMORY_COST, help = "Argon2id memory cost in KiB")]
+    memory_cost: u32,
+
+    #[arg(long, default_value_t = ARGON2_TIME_COST, help = "Argon2id time cost (iterations)")]
+    time_cost: u32,
+
+    #[arg(long, help = "Upload snapshots as a single encrypted archive, hiding file names from the server")]
+    seal: bool,
+
+    #[arg(long, conflicts_with = "seal", help = "Encrypt only the values of env files, keeping keys and comments readable")]
+    values: bool,
+}
+
+#[derive(Args, Debug)]
+struct RekeyArgs {
+    #[arg(long, short, num_args = 0..=1, help = "New password of the repo, prompted for when omitted (passing it inline is deprecated)")]
+    password: Option<Option<String>>,
+
+    #[arg(long, conflicts_with = "password", help = "Read the new password from stdin")]
+    password_stdin: bool,
+
+    #[arg(long, value_enum, help = "Key derivation function to switch to")]
+    kdf: Option<KdfKind>,
+
+    #[arg(long, default_value_t = ARGON2_MEMORY_COST, help = "Argon2id memory cost in KiB")]
+    memory_cost: u32,
+
+    #[arg(long, default_value_t = ARGON2_TIME_COST, help = "Argon2id time cost (iterations)")]
+    time_cost: u32,
+}
+
+#[derive(Args, Debug, Default)]
+struct FetchArgs {
+    #[arg(long, help = "Revision of the snapshot to fetch, defaults to the latest")]
+    revision: Option<u32>,
+
+    #[arg(long, help = "Fetch the latest snapshot uploaded from this branch instead of HEAD's")]
+    branch: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct HistoryArgs {
+    #[arg(long, help = "Commit whose revisions to list, defaults to HEAD")]
+    commit: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct RollbackArgs {
+    #[arg(help = "Revision to restore, as listed by `enva history`")]
+    revision: u32,
+
+    #[arg(long, help = "Commit whose snapshot to roll back, defaults to HEAD")]
+    commit: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[arg(long, help = "Commit whose snapshot to use, defaults to HEAD")]
+    commit: Option<String>,
+
+    #[arg(long, help = "Env file to load, repeatable; later files override earlier ones [default: .env .env.local]")]
+    file: Vec<String>,
+
+    #[arg(last = true, required = true, help = "Command to run")]
+    command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    #[arg(long, value_enum, help = "Output format")]
+    format: ExportFormat,
+
+    #[arg(long, help = "Commit whose snapshot to export, defaults to HEAD")]
+    commit: Option<String>,
+
+    #[arg(long, help = "Env file to load, repeatable; later files override earlier ones [default: .env .env.local]")]
+    file: Vec<String>,
+
+    #[arg(long, help = "Name of the Kubernetes Secret, defaults to the repo name made DNS-1123 safe")]
+    name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ImportArgs {
+    #[arg(help = "File to import, or - for stdin")]
+    source: PathBuf,
+
+    #[arg(long, value_enum, help = "Input format, guessed from the extension when omitted")]
+    format: Option<ImportFormat>,
+
+    #[arg(long, default_value = ".env", help = "Env file to merge the variables into")]
+    file: String,
+}
+
+#[derive(Args, Debug)]
+struct GetArgs {
+    #[arg(help = "Name of the variable")]
+    key: String,
+
+    #[arg(long, help = "Env file to read, repeatable; later files override earlier ones [default: .env .env.local]")]
+    file: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct SetArgs {
+    #[arg(required = true, value_name = "KEY=VALUE", help = "Variables to set")]
+    assignments: Vec<String>,
+
+    #[arg(long, default_value = ".env", help = "Env file to edit")]
+    file: String,
+
+    #[arg(long, help = "Upload a snapshot for HEAD right away")]
+    push: bool,
+}
+
+#[derive(Args, Debug)]
+struct UnsetArgs {
+    #[arg(required = true, value_name = "KEY", help = "Variables to remove")]
+    keys: Vec<String>,
+
+    #[arg(long, default_value = ".env", help = "Env file to edit")]
+    file: String,
+
+    #[arg(long, help = "Upload a snapshot for HEAD right away")]
+    push: bool,
+}
+
+#[derive(Args, Debug)]
+struct KeyArgs {
+    #[command(subcommand)]
+    command: KeyCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    #[command(about = "Generate a local X25519 identity and an Ed25519 signing key")]
+    Generate(KeyGenerateArgs),
+    #[command(about = "Print the public key of the local identity")]
+    Show(KeyShowArgs),
+    #[command(about = "Split the repo key into Shamir shares for recovery by a quorum of admins")]
+    Split(KeySplitArgs),
+    #[command(about = "Rebuild the repo key from Shamir shares and save it to the keychain")]
+    Recover(KeyRecoverArgs),
+}
+
+#[derive(Args, Debug)]
+struct KeySplitArgs {
+    #[arg(long, help = "Number of shares to create")]
+    shares: u8,
+
+    #[arg(long, help = "Number of shares needed to recover the key")]
+    threshold: u8,
+}
+
+#[derive(Args, Debug)]
+struct KeyRecoverArgs {
+    #[arg(help = "Shares printed by `enva key split`, prompted for when omitted")]
+    shares: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct KeyShowArgs {
+    #[arg(long, help = "Print the public signing key instead")]
+    signing: bool,
+}
+
+#[derive(Args, Debug)]
+struct KeyGenerateArgs {
+    #[arg(long, help = "Replace an existing identity")]
+    force: bool,
+}
+
+#[derive(Args, Debug)]
+struct RecipientsArgs {
+    #[command(subcommand)]
+    command: RecipientsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum RecipientsCommand {
+    #[command(about = "Wrap the repo key for a teammate's public key")]
+    Add(RecipientAddArgs),
+    #[command(about = "Remove a teammate from the repo recipients")]
+    Remove(RecipientRemoveArgs),
+    #[command(about = "List the repo recipients")]
+    List,
+}
+
+#[derive(Args, Debug)]
+struct RecipientAddArgs {
+    #[arg(
+        help = "Name of the recipient",
+        required_unless_present_any = ["github", "team"],
+        requires = "public_key"
+    )]
+    name: Option<String>,
+
+    #[arg(help = "Public key printed by `enva key show`")]
+    public_key: Option<String>,
+
+    #[arg(long, help = "GitHub user whose ed25519 SSH keys become recipients")]
+    github: Vec<String>,
+
+    #[arg(long, help = "GitHub team (org/team) whose members' ed25519 SSH keys become recipients")]
+    team: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct RecipientRemoveArgs {
+    #[arg(help = "Name of the recipient")]
+    name: String,
+}
+
+#[derive(Args, Debug)]
+struct SignersArgs {
+    #[command(subcommand)]
+    command: SignersCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SignersCommand {
+    #[command(about = "Trust a teammate's signing key for snapshots")]
+    Add(SignerAddArgs),
+    #[command(about = "Stop trusting a teammate's signing key")]
+    Remove(SignerRemoveArgs),
+    #[command(about = "List the trusted signers")]
+    List,
+}
+
+#[derive(Args, Debug)]
+struct SignerAddArgs {
+    #[arg(
+        help = "Name of the signer",
+        required_unless_present_any = ["github"],
+        requires = "public_key"
+    )]
+    name: Option<String>,
+
+    #[arg(help = "Public key printed by `enva key show --signing`")]
+    public_key: Option<String>,
+
+    #[arg(long, help = "GitHub user whose ed25519 SSH keys become trusted signers")]
+    github: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct SignerRemoveArgs {
+    #[arg(help = "Name of the signer")]
+    name: String,
 }
 
 #[tokio::main]
@@ -49,6 +347,34 @@ async fn main() {
         Command::Login(args) => handlers::login(args),
         Command::Active(args) => handlers::active(args).await,
         Command::Commit => handlers::commit().await,
-        Command::Fetch => handlers::fetch().await,
+        Command::Push => handlers::push().await,
+        Command::Fetch(args) => handlers::fetch(args).await,
+        Command::Key(args) => match args.command {
+            KeyCommand::Generate(args) => handlers::key_generate(args),
+            KeyCommand::Show(args) => handlers::key_show(args),
+            KeyCommand::Split(args) => handlers::key_split(args).await,
+            KeyCommand::Recover(args) => handlers::key_recover(args).await,
+        },
+        Command::Recipients(args) => match args.command {
+            RecipientsCommand::Add(args) => handlers::recipients_add(args).await,
+            RecipientsCommand::Remove(args) => handlers::recipients_remove(args).await,
+            RecipientsCommand::List => handlers::recipients_list(),
+        },
+        Command::Rekey(args) => handlers::rekey(args).await,
+        Command::Signers(args) => match args.command {
+            SignersCommand::Add(args) => handlers::signers_add(args).await,
+            SignersCommand::Remove(args) => handlers::signers_remove(args),
+            SignersCommand::List => handlers::signers_list(),
+        },
+        Command::Log => handlers::log().await,
+        Command::History(args) => handlers::history(args).await,
+        Command::Branches => handlers::branches().await,
+        Command::Rollback(args) => handlers::rollback(args).await,
+        Command::Run(args) => handlers::run(args).await,
+        Command::Export(args) => handlers::export(args).await,
+        Command::Import(args) => handlers::import(args).await,
+        Command::Get(args) => handlers::get(args),
+        Command::Set(args) => handlers::set(args).await,
+        Command::Unset(args) => handlers::unset(args).await,
     }
 }
@@ -0,0 +1,225 @@
+use aes_gcm::aead::OsRng;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use crate::encryption::ARCHIVE_NAME;
+use crate::keystore;
+use crate::recipients::load_ssh_signing_key;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier, VerifyingKey};
+use enva_shared::models::{EnvFile, Manifest, SignedManifest};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use toml_edit::{ArrayOfTables, DocumentMut, Table, value};
+use zeroize::Zeroize;
+
+const SIGNING_KEY_ACCOUNT: &str = "signing-key";
+
+pub struct Signer {
+    pub name: String,
+    pub public_key: String,
+}
+
+pub fn generate_signing_key() -> String {
+    let signing_key = SigningKey::generate(&mut OsRng);
+
+    keystore::set(SIGNING_KEY_ACCOUNT, &STANDARD.encode(signing_key.to_bytes()))
+        .expect("Failed to save signing key to keychain");
+
+    verifying_key(&signing_key)
+}
+
+// Falls back to the local ed25519 SSH key, the same key GitHub publishes for the user
+pub fn load_signing_key() -> Option<SigningKey> {
+    load_keychain_signing_key().or_else(load_ssh_signing_key)
+}
+
+pub fn load_keychain_signing_key() -> Option<SigningKey> {
+    let encoded = keystore::get(SIGNING_KEY_ACCOUNT).ok()?;
+
+    let mut bytes: [u8; 32] = STANDARD.decode(encoded).ok()?.try_into().ok()?;
+    let signing_key = SigningKey::from_bytes(&bytes);
+    bytes.zeroize();
+
+    Some(signing_key)
+}
+
+pub fn verifying_key(signing_key: &SigningKey) -> String {
+    STANDARD.encode(signing_key.verifying_key().as_bytes())
+}
+
+pub fn ssh_public_key_to_signer(ssh_public_key: &str) -> Option<String> {
+    let key = ssh_key::PublicKey::from_openssh(ssh_public_key).ok()?;
+
+    Some(STANDARD.encode(key.key_data().ed25519()?.0))
+}
+
+pub fn hash_content(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+// A sealed snapshot is signed as a single archive, so the manifest doesn't leak file names.
+// The archive carries the file modes inside its encrypted, authenticated contents.
+fn uploaded_files<'a>(env_files: &'a HashMap<String, EnvFile>, archive: Option<&'a [u8]>) -> Vec<(&'a str, &'a [u8], Option<u32>)> {
+    match archive {
+        Some(archive) => vec![(ARCHIVE_NAME, archive, None)],
+        None => env_files.iter().map(|(k, v)| (k.as_str(), v.content.as_slice(), v.mode)).collect(),
+    }
+}
+
+pub fn build_manifest(
+    repo: &str,
+    commit_id: &str,
+    revision: u32,
+    parent: Option<String>,
+    author: &str,
+    env_files: &HashMap<String, EnvFile>,
+    archive: Option<&[u8]>,
+) -> Manifest {
+    let files = uploaded_files(env_files, archive);
+
+    Manifest {
+        repo: repo.to_string(),
+        commit_id: commit_id.to_string(),
+        parent,
+        author: author.to_string(),
+        files: files
+            .iter()
+            .map(|(name, content, _)| (name.to_string(), hash_content(content)))
+            .collect(),
+        modes: files
+            .iter()
+            .filter_map(|(name, _, mode)| mode.map(|mode| (name.to_string(), mode)))
+            .collect(),
+        revision,
+    }
+}
+
+pub fn sign(manifest: &Manifest, signing_key: &SigningKey) -> SignedManifest {
+    let manifest = serde_json::to_string(manifest).expect("Failed to serialize manifest");
+    let signature = signing_key.sign(manifest.as_bytes());
+
+    SignedManifest {
+        manifest,
+        signer: verifying_key(signing_key),
+        signature: STANDARD.encode(signature.to_bytes()),
+    }
+}
+
+pub fn verify_signature(signed: &SignedManifest) -> Result<Manifest, String> {
+    let signer: [u8; 32] = STANDARD
+        .decode(&signed.signer)
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or("Invalid signer key in manifest")?;
+    let signer = VerifyingKey::from_bytes(&signer).map_err(|e| e.to_string())?;
+
+    let signature: [u8; 64] = STANDARD
+        .decode(&signed.signature)
+        .ok()
+        .and_then(|s| s.try_into().ok())
+        .ok_or("Invalid signature in manifest")?;
+
+    signer
+        .verify(signed.manifest.as_bytes(), &Signature::from_bytes(&signature))
+        .map_err(|_| "Manifest signature is invalid".to_string())?;
+
+    serde_json::from_str(&signed.manifest).map_err(|e| e.to_string())
+}
+
+// Returns who signed the snapshot, or None when it is unsigned and the repo
+// has no trusted signers configured
+pub fn verify_snapshot(
+    doc: &DocumentMut,
+    repo: &str,
+    commit_id: &str,
+    revision: u32,
+    env_files: &HashMap<String, EnvFile>,
+    archive: Option<&[u8]>,
+    signed: Option<&SignedManifest>,
+) -> Result<Option<String>, String> {
+    let signers = read_signers(doc);
+
+    let Some(signed) = signed else {
+        if signers.is_empty() {
+            return Ok(None);
+        }
+
+        return Err("Snapshot is not signed, but this repo only trusts signed snapshots".to_string());
+    };
+
+    let manifest = verify_signature(signed)?;
+
+    if manifest.repo != repo || manifest.commit_id != commit_id {
+        return Err(format!(
+            "Manifest belongs to {}@{}, not {}@{}",
+            manifest.repo, manifest.commit_id, repo, commit_id
+        ));
+    }
+
+    if manifest.revision != revision {
+        return Err(format!(
+            "Manifest was signed for revision {}, but the server returned it as revision {}",
+            manifest.revision, revision
+        ));
+    }
+
+    let files: HashMap<&str, (&[u8], Option<u32>)> = uploaded_files(env_files, archive)
+        .into_iter()
+        .map(|(name, content, mode)| (name, (content, mode)))
+        .collect();
+
+    for name in files.keys() {
+        if !manifest.files.contains_key(*name) {
+            return Err(format!("{} was added to the snapshot after it was signed", name));
+        }
+    }
+
+    for (name, hash) in &manifest.files {
+        match files.get(name.as_str()) {
+            None => return Err(format!("{} was removed from the snapshot after it was signed", name)),
+            Some((content, _)) if &hash_content(content) != hash => {
+                return Err(format!("{} was modified after the snapshot was signed", name));
+            }
+            Some((_, mode)) if manifest.modes.get(name).copied() != *mode => {
+                return Err(format!("The permissions of {} were changed after the snapshot was signed", name));
+            }
+            Some(_) => {}
+        }
+    }
+
+    let signer = signers.iter().find(|s| s.public_key == signed.signer);
+
+    match signer {
+        Some(signer) => Ok(Some(signer.name.clone())),
+        None if signers.is_empty() => Ok(Some(format!("{} (untrusted key)", manifest.author))),
+        None => Err(format!("Snapshot was signed by an untrusted key {}", signed.signer)),
+    }
+}
+
+pub fn read_signers(doc: &DocumentMut) -> Vec<Signer> {
+    doc.get("signers")
+        .and_then(|item| item.as_array_of_tables())
+        .map(|tables| {
+            tables
+                .iter()
+                .filter_map(|table| {
+                    Some(Signer {
+                        name: table.get("name")?.as_str()?.to_string(),
+                        public_key: table.get("public_key")?.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn write_signers(doc: &mut DocumentMut, signers: &[Signer]) {
+    let mut tables = ArrayOfTables::new();
+
+    for signer in signers {
+        let mut table = Table::new();
+        table["name"] = value(signer.name.as_str());
+        table["public_key"] = value(signer.public_key.as_str());
+        tables.push(table);
+    }
+
+    doc["signers"] = toml_edit::Item::ArrayOfTables(tables);
+}
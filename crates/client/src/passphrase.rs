@@ -0,0 +1,52 @@
+use log::{error, warn};
+use std::env;
+use std::io::Read;
+
+pub const PASSWORD_ENV: &str = "ENVA_PASSWORD";
+
+// Resolves a password from, in order: stdin, the deprecated inline argument, ENVA_PASSWORD and
+// finally a hidden prompt. `arg` is None when `--password` wasn't passed at all.
+pub fn read_password(arg: Option<Option<String>>, stdin: bool, prompt: &str, confirm: bool) -> Option<String> {
+    if stdin {
+        let mut password = String::new();
+        std::io::stdin()
+            .read_to_string(&mut password)
+            .expect("Failed to read password from stdin");
+
+        return Some(password.trim_end_matches(['\n', '\r']).to_string());
+    }
+
+    if let Some(Some(password)) = arg {
+        warn!("Passing the password as an argument leaks it to shell history and `ps`, use the prompt, --password-stdin or {PASSWORD_ENV} instead");
+        return Some(password);
+    }
+
+    if let Ok(password) = env::var(PASSWORD_ENV) {
+        return Some(password);
+    }
+
+    arg.map(|_| prompt_password(prompt, confirm))
+}
+
+pub fn prompt_password(prompt: &str, confirm: bool) -> String {
+    loop {
+        let password = rpassword::prompt_password(prompt).expect("Failed to read password");
+
+        if password.is_empty() {
+            error!("Password can't be empty");
+            continue;
+        }
+
+        if !confirm {
+            return password;
+        }
+
+        let confirmation = rpassword::prompt_password("Confirm password: ").expect("Failed to read password");
+
+        if password == confirmation {
+            return password;
+        }
+
+        error!("Passwords don't match, try again");
+    }
+}
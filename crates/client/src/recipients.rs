@@ -0,0 +1,339 @@
+use aes_gcm::{
+    Aes256Gcm,
+    aead::{Aead, KeyInit, OsRng, rand_core::RngCore},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use directories::BaseDirs;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use crate::keystore;
+use log::warn;
+use sha2::Sha256;
+use std::path::PathBuf;
+use toml_edit::{ArrayOfTables, DocumentMut, Table, value};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+const IDENTITY_ACCOUNT: &str = "identity";
+const WRAP_INFO: &str = "photon-hq/enva x25519";
+const DATA_KEY_LEN: usize = 32;
+
+pub struct Recipient {
+    pub name: String,
+    pub public_key: String,
+    pub wrapped_key: String,
+}
+
+pub fn generate_identity() -> String {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+
+    keystore::set(IDENTITY_ACCOUNT, &STANDARD.encode(secret.to_bytes())).expect("Failed to save identity to keychain");
+
+    STANDARD.encode(public_key.as_bytes())
+}
+
+pub fn load_identity() -> Option<StaticSecret> {
+    let encoded = keystore::get(IDENTITY_ACCOUNT).ok()?;
+
+    let mut bytes: [u8; 32] = STANDARD.decode(encoded).ok()?.try_into().ok()?;
+    let secret = StaticSecret::from(bytes);
+    bytes.zeroize();
+
+    Some(secret)
+}
+
+pub fn load_ssh_signing_key() -> Option<SigningKey> {
+    let path = std::env::var("ENVA_SSH_KEY")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(|| BaseDirs::new().map(|dirs| dirs.home_dir().join(".ssh").join("id_ed25519")))?;
+
+    let mut key = ssh_key::PrivateKey::read_openssh_file(&path).ok()?;
+
+    if key.is_encrypted() {
+        let Ok(passphrase) = std::env::var("ENVA_SSH_PASSPHRASE") else {
+            warn!("SSH key {} is encrypted, set ENVA_SSH_PASSPHRASE to use it", path.display());
+            return None;
+        };
+
+        key = key.decrypt(passphrase).ok()?;
+    }
+
+    let keypair = key.key_data().ed25519()?;
+
+    Some(SigningKey::from_bytes(&keypair.private.to_bytes()))
+}
+
+// Ed25519 SSH keys are converted to their X25519 form, so a teammate's GitHub
+// key can receive the repo key and their local SSH key can unwrap it
+pub fn load_ssh_identity() -> Option<StaticSecret> {
+    load_ssh_signing_key().map(|key| StaticSecret::from(key.to_scalar_bytes()))
+}
+
+pub fn load_identities() -> Vec<StaticSecret> {
+    load_identity().into_iter().chain(load_ssh_identity()).collect()
+}
+
+pub fn ssh_public_key_to_recipient(ssh_public_key: &str) -> Option<String> {
+    let key = ssh_key::PublicKey::from_openssh(ssh_public_key).ok()?;
+    let verifying_key = VerifyingKey::from_bytes(&key.key_data().ed25519()?.0).ok()?;
+
+    Some(STANDARD.encode(verifying_key.to_montgomery().as_bytes()))
+}
+
+pub fn public_key(identity: &StaticSecret) -> String {
+    STANDARD.encode(PublicKey::from(identity).as_bytes())
+}
+
+pub fn generate_data_key() -> Vec<u8> {
+    let mut key = vec![0u8; DATA_KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+pub fn wrap_key(data_key: &[u8], public_key: &str) -> Result<String, String> {
+    let recipient = decode_public_key(public_key)?;
+
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+
+    let mut wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral_public, &recipient);
+    let cipher = Aes256Gcm::new_from_slice(&wrap_key).unwrap();
+    wrap_key.zeroize();
+
+    // Every wrap uses a fresh ephemeral key, so a fixed nonce is never reused under the same key
+    let ciphertext = cipher
+        .encrypt(&[0u8; 12].into(), data_key)
+        .map_err(|e| e.to_string())?;
+
+    let mut output = Vec::new();
+    output.extend_from_slice(ephemeral_public.as_bytes());
+    output.extend_from_slice(&ciphertext);
+
+    Ok(STANDARD.encode(output))
+}
+
+pub fn unwrap_key(wrapped_key: &str, identity: &StaticSecret) -> Result<Vec<u8>, String> {
+    let data = STANDARD.decode(wrapped_key).map_err(|e| e.to_string())?;
+
+    if data.len() < 32 {
+        return Err("Wrapped key is too short".to_string());
+    }
+
+    let (ephemeral_public, ciphertext) = data.split_at(32);
+    let ephemeral_public: [u8; 32] = ephemeral_public.try_into().unwrap();
+    let ephemeral_public = PublicKey::from(ephemeral_public);
+
+    let shared = identity.diffie_hellman(&ephemeral_public);
+
+    let mut wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral_public, &PublicKey::from(identity));
+    let cipher = Aes256Gcm::new_from_slice(&wrap_key).unwrap();
+    wrap_key.zeroize();
+
+    cipher
+        .decrypt(&[0u8; 12].into(), ciphertext)
+        .map_err(|_| "Failed to unwrap data key: it was not wrapped for this identity".to_string())
+}
+
+fn derive_wrap_key(shared: &[u8], ephemeral_public: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = Vec::new();
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO.as_bytes(), &mut key)
+        .expect("HKDF output length is valid");
+
+    key
+}
+
+fn decode_public_key(public_key: &str) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(public_key.trim())
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| format!("Invalid public key: {}", public_key))?;
+
+    Ok(PublicKey::from(bytes))
+}
+
+pub fn read_recipients(doc: &DocumentMut) -> Vec<Recipient> {
+    doc.get("recipients")
+        .and_then(|item| item.as_array_of_tables())
+        .map(|tables| {
+            tables
+                .iter()
+                .filter_map(|table| {
+                    Some(Recipient {
+                        name: table.get("name")?.as_str()?.to_string(),
+                        public_key: table.get("public_key")?.as_str()?.to_string(),
+                        wrapped_key: table.get("wrapped_key")?.as_str()?.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn write_recipients(doc: &mut DocumentMut, recipients: &[Recipient]) {
+    let mut tables = ArrayOfTables::new();
+
+    for recipient in recipients {
+        let mut table = Table::new();
+        table["name"] = value(recipient.name.as_str());
+        table["public_key"] = value(recipient.public_key.as_str());
+        table["wrapped_key"] = value(recipient.wrapped_key.as_str());
+        tables.push(table);
+    }
+
+    doc["recipients"] = toml_edit::Item::ArrayOfTables(tables);
+}
+
+pub fn unwrap_repo_key(doc: &DocumentMut) -> Result<Vec<u8>, String> {
+    let identities = load_identities();
+
+    if identities.is_empty() {
+        return Err("No identity found, run `enva key generate` or add an ed25519 SSH key".to_string());
+    }
+
+    let recipients = read_recipients(doc);
+
+    for identity in &identities {
+        let own_public_key = public_key(identity);
+
+        if let Some(recipient) = recipients.iter().find(|r| r.public_key == own_public_key) {
+            return unwrap_key(&recipient.wrapped_key, identity);
+        }
+    }
+
+    Err("You are not a recipient of this repo, ask a teammate to run `enva recipients add`".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use ssh_key::{LineEnding, PrivateKey, private::Ed25519Keypair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Answers GitHub API GETs from `routes` (path without query: JSON body) on a local port
+    async fn mock_github(routes: Vec<(String, serde_json::Value)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request);
+                let target = request.split_whitespace().nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+
+                let (status, body) = match routes.iter().find(|(route, _)| route == path) {
+                    Some((_, body)) => ("200 OK", body.to_string()),
+                    None => ("404 Not Found", json!({ "message": "Not Found" }).to_string()),
+                };
+
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn github_user(login: &str) -> serde_json::Value {
+        let url = format!("https://api.github.com/users/{login}");
+
+        json!({
+            "login": login,
+            "id": 1,
+            "node_id": "MDQ6VXNlcjE=",
+            "avatar_url": "https://avatars.githubusercontent.com/u/1",
+            "gravatar_id": "",
+            "url": url,
+            "html_url": format!("https://github.com/{login}"),
+            "followers_url": format!("{url}/followers"),
+            "following_url": format!("{url}/following"),
+            "gists_url": format!("{url}/gists"),
+            "starred_url": format!("{url}/starred"),
+            "subscriptions_url": format!("{url}/subscriptions"),
+            "organizations_url": format!("{url}/orgs"),
+            "repos_url": format!("{url}/repos"),
+            "events_url": format!("{url}/events"),
+            "received_events_url": format!("{url}/received_events"),
+            "type": "User",
+            "site_admin": false,
+        })
+    }
+
+    #[tokio::test]
+    async fn team_member_ssh_keys_receive_the_repo_key() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let private_key = PrivateKey::from(Ed25519Keypair::from(&signing_key));
+        let ssh_public_key = private_key.public_key().to_openssh().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("enva-recipients-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join("id_ed25519");
+        private_key.write_openssh_file(&key_path, LineEnding::LF).unwrap();
+
+        let url = mock_github(vec![
+            ("/orgs/acme/teams/devs/members".to_string(), json!([github_user("alice")])),
+            (
+                "/users/alice/keys".to_string(),
+                json!([
+                    { "id": 1, "key": "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQC7" },
+                    { "id": 2, "key": ssh_public_key },
+                ]),
+            ),
+        ])
+        .await;
+
+        // Only this test reads these variables
+        unsafe {
+            std::env::set_var("ENVA_GITHUB_API_URL", &url);
+            std::env::set_var("ENVA_SSH_KEY", &key_path);
+        }
+
+        let members = enva_shared::fetch_team_members("token", "acme", "devs").await.unwrap();
+        assert_eq!(members, ["alice"]);
+
+        let recipients: Vec<String> = enva_shared::fetch_ssh_keys("token", "alice")
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|key| ssh_public_key_to_recipient(key))
+            .collect();
+        assert_eq!(recipients.len(), 1, "only the ed25519 key can be a recipient");
+
+        let data_key = generate_data_key();
+        let wrapped = wrap_key(&data_key, &recipients[0]).unwrap();
+
+        let identity = load_ssh_identity().expect("The SSH key is loaded from ENVA_SSH_KEY");
+        assert_eq!(public_key(&identity), recipients[0]);
+        assert_eq!(unwrap_key(&wrapped, &identity).unwrap(), data_key);
+
+        let other = StaticSecret::random_from_rng(OsRng);
+        assert!(unwrap_key(&wrapped, &other).is_err());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
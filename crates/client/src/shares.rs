@@ -0,0 +1,189 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use zeroize::Zeroize;
+
+// A share is its x coordinate followed by one y byte per key byte. The field is GF(256) with the
+// 0x11d polynomial, the same layout the sharks crate used, so shares split by older versions still recover
+struct Share {
+    x: u8,
+    y: Vec<u8>,
+}
+
+// Shares are printed as `owner/repo:threshold:base64`, so they can't be mixed up between
+// repos and recovery knows how many are needed
+pub fn split_key(repo: &str, key: &[u8], shares: u8, threshold: u8) -> Vec<String> {
+    // Every coefficient is drawn uniformly from the whole field, zero included. Restricting them
+    // to non-zero values (as sharks did) lets fewer than `threshold` shares leak information
+    let degree = threshold as usize - 1;
+    let mut coefficients = vec![0u8; key.len() * degree];
+    OsRng.fill_bytes(&mut coefficients);
+
+    let result = (1..=shares)
+        .map(|x| {
+            let mut share = vec![x];
+
+            for (i, secret) in key.iter().enumerate() {
+                let terms = &coefficients[i * degree..(i + 1) * degree];
+                // Horner's method, highest degree first
+                let y = terms.iter().rev().fold(0, |acc, c| gf_mul(acc, x) ^ c);
+                share.push(gf_mul(y, x) ^ secret);
+            }
+
+            let encoded = format!("{}:{}:{}", repo, threshold, STANDARD.encode(&share));
+            share.zeroize();
+            encoded
+        })
+        .collect();
+
+    coefficients.zeroize();
+    result
+}
+
+pub fn share_threshold(repo: &str, share: &str) -> Result<u8, String> {
+    parse_share(repo, share).map(|(threshold, _)| threshold)
+}
+
+pub fn recover_key(repo: &str, shares: &[String]) -> Result<Vec<u8>, String> {
+    let mut threshold = 0;
+    let mut parsed: Vec<Share> = Vec::new();
+
+    for share in shares {
+        let (share_threshold, share) = parse_share(repo, share)?;
+
+        threshold = threshold.max(share_threshold);
+
+        if let Some(existing) = parsed.iter().find(|s| s.x == share.x) {
+            if existing.y != share.y {
+                return Err(format!("Two different shares have the same index {}", share.x));
+            }
+            continue;
+        }
+
+        parsed.push(share);
+    }
+
+    if parsed.len() < threshold as usize {
+        return Err(format!("Not enough distinct shares, {} are needed", threshold));
+    }
+
+    let parsed = &parsed[..threshold as usize];
+    let len = parsed[0].y.len();
+
+    if parsed.iter().any(|s| s.y.len() != len) {
+        return Err("The shares have different lengths".to_string());
+    }
+
+    // Lagrange interpolation at x = 0, where subtraction in GF(256) is xor
+    let key = (0..len)
+        .map(|i| {
+            parsed.iter().fold(0, |acc, share| {
+                let basis = parsed
+                    .iter()
+                    .filter(|other| other.x != share.x)
+                    .fold(1, |b, other| gf_mul(b, gf_mul(other.x, gf_inv(other.x ^ share.x))));
+                acc ^ gf_mul(share.y[i], basis)
+            })
+        })
+        .collect();
+
+    Ok(key)
+}
+
+fn parse_share(repo: &str, share: &str) -> Result<(u8, Share), String> {
+    let mut parts = share.trim().rsplitn(3, ':');
+
+    let (Some(data), Some(threshold), Some(share_repo)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("Invalid share, expected owner/repo:threshold:data".to_string());
+    };
+
+    if share_repo != repo {
+        return Err(format!("This share belongs to {}, not {}", share_repo, repo));
+    }
+
+    let threshold: u8 = threshold.parse().map_err(|_| "Invalid threshold in share")?;
+    let data = STANDARD.decode(data).map_err(|_| "Invalid share data")?;
+
+    match data.split_first() {
+        Some((&x, y)) if x != 0 && !y.is_empty() && threshold > 0 => Ok((threshold, Share { x, y: y.to_vec() })),
+        _ => Err("Invalid share data".to_string()),
+    }
+}
+
+// Branch-free so the timing doesn't depend on the secret bytes
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ (0x1d & (a >> 7).wrapping_neg());
+        b >>= 1;
+    }
+
+    product
+}
+
+// a^254 is the inverse of a, since every non-zero element satisfies a^255 = 1
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29,
+        30, 31,
+    ];
+
+    #[test]
+    fn any_threshold_subset_recovers() {
+        let shares = split_key("o/r", &KEY, 5, 3);
+
+        for skip in 0..5 {
+            for other in skip + 1..5 {
+                let subset: Vec<String> = (0..5).filter(|i| *i != skip && *i != other).map(|i| shares[i].clone()).collect();
+                assert_eq!(recover_key("o/r", &subset).unwrap(), KEY);
+            }
+        }
+    }
+
+    #[test]
+    fn too_few_shares_fail() {
+        let shares = split_key("o/r", &KEY, 3, 2);
+        let err = recover_key("o/r", &[shares[0].clone(), shares[0].clone()]).unwrap_err();
+
+        assert_eq!(err, "Not enough distinct shares, 2 are needed");
+    }
+
+    #[test]
+    fn recovers_shares_split_by_sharks() {
+        let shares = [
+            "o/r:2:AbB0sBuvE1K8st1yQDwPLxXX4cyuJ3Bz5oy2Vp0X5TIT",
+            "o/r:2:An3rezNPKa5sYbz6nWwJTDuD7LN0ct/c6C1aggoK8EYH",
+            "o/r:2:A82eySvkP/rX22iC1lwLbSFEHG3JQbq5Gbn1zowBCGoL",
+        ];
+
+        assert_eq!(recover_key("o/r", &[shares[0].to_string(), shares[2].to_string()]).unwrap(), KEY);
+        assert_eq!(recover_key("o/r", &[shares[2].to_string(), shares[1].to_string()]).unwrap(), KEY);
+    }
+
+    #[test]
+    fn field_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+}
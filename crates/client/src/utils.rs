@@ -1,11 +1,13 @@
 use directories::ProjectDirs;
+use enva_shared::dotenv::Dotenv;
+use enva_shared::models::EnvFile;
 use git2::Repository;
 use log::{error, info};
 use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use toml_edit::DocumentMut;
+use toml_edit::{DocumentMut, value};
 use which::which;
 
 pub fn get_enva_executable_path() -> Option<PathBuf> {
@@ -30,6 +32,50 @@ pub fn write_config(doc: DocumentMut) {
     std::fs::write(&config_path, doc.to_string()).expect("Failed to write config file");
 }
 
+pub fn get_repo_config_path() -> PathBuf {
+    env::current_dir()
+        .expect("Failed to get current directory")
+        .join(".enva.toml")
+}
+
+pub fn read_repo_config() -> DocumentMut {
+    let text = std::fs::read_to_string(get_repo_config_path()).unwrap_or_else(|_| String::new());
+    text.parse::<DocumentMut>().expect("Failed to parse .enva.toml")
+}
+
+pub fn write_repo_config(doc: DocumentMut) {
+    std::fs::write(get_repo_config_path(), doc.to_string()).expect("Failed to write .enva.toml");
+}
+
+pub fn is_encrypted(repo_url: &str) -> bool {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    read_config()
+        .get(&format!("{owner}:{repo_name}"))
+        .and_then(|repo| repo.get("encrypted"))
+        .and_then(|encrypted| encrypted.as_bool())
+        .unwrap_or(false)
+}
+
+// Generation of the repo key saved in the keychain, bumped by every `enva rekey`
+pub fn key_generation(repo_url: &str) -> u32 {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    read_config()
+        .get(&format!("{owner}:{repo_name}"))
+        .and_then(|repo| repo.get("key_generation"))
+        .and_then(|generation| generation.as_integer())
+        .unwrap_or(0) as u32
+}
+
+pub fn set_key_generation(repo_url: &str, generation: u32) {
+    let (owner, repo_name) = enva_shared::parse_github_repo(repo_url).expect("Invalid repo URL");
+
+    let mut doc = read_config();
+    doc[&format!("{owner}:{repo_name}")]["key_generation"] = value(generation as i64);
+    write_config(doc);
+}
+
 pub fn get_token() -> Option<String> {
     let doc = read_config();
     doc["auth"]["gh_token"].as_str().map(|s| s.to_string())
@@ -84,9 +130,9 @@ pub async fn check_ownership() {
 
         info!("Remote URL: {}", repo_url);
 
-        if !enva_shared::check_ownership(&get_token().expect("You need to login first"), &repo_url)
+        if enva_shared::check_ownership(&get_token().expect("You need to login first"), &repo_url)
             .await
-            .unwrap_or(false)
+            .is_err()
         {
             panic!("You does not have ownership of the repository");
         }
@@ -99,7 +145,7 @@ pub async fn check_ownership() {
     }
 }
 
-pub fn read_env_file() -> HashMap<String, String> {
+pub fn read_env_file() -> HashMap<String, EnvFile> {
     let mut env_files = HashMap::new();
 
     let current_dir = env::current_dir().expect("Failed to get current directory");
@@ -112,14 +158,126 @@ pub fn read_env_file() -> HashMap<String, String> {
         if path.is_file()
             && let Some(file_name) = path.file_name().and_then(|n| n.to_str())
             && file_name.starts_with(".env")
+            && file_name != ".enva.toml"
         {
             info!("Reading env file: {}", path.display());
 
-            if let Ok(content) = fs::read_to_string(&path) {
-                env_files.insert(file_name.to_string(), content);
+            match fs::read(&path) {
+                Ok(content) => {
+                    let mode = file_mode(&path);
+                    env_files.insert(file_name.to_string(), EnvFile { content, mode });
+                }
+                Err(e) => error!("Failed to read {}: {}", path.display(), e),
             }
         }
     }
 
     env_files
-}
\ No newline at end of file
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::metadata(path).ok().map(|metadata| metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+// Only `.env*` files in the repo root are picked up by `read_env_file`
+pub fn check_synced_file(file_name: &str) {
+    if !file_name.starts_with(".env") || file_name.contains(['/', '\\']) || file_name == ".enva.toml" {
+        panic!("{} isn't synced, the target must be a .env* file in the repo root", file_name);
+    }
+}
+
+// Reads a local env file for editing, an empty one when it doesn't exist yet
+pub fn read_dotenv(file_name: &str) -> Dotenv {
+    match fs::read_to_string(file_name) {
+        Ok(content) => Dotenv::parse(&content).unwrap_or_else(|e| panic!("Failed to parse {}: {}", file_name, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Dotenv::default(),
+        Err(e) => panic!("Failed to read {}: {}", file_name, e),
+    }
+}
+
+// Writes an edited env file back, keeping the mode of an existing file
+pub fn write_dotenv(file_name: &str, doc: &Dotenv) {
+    let file = EnvFile {
+        content: doc.to_string().into_bytes(),
+        mode: file_mode(Path::new(file_name)),
+    };
+
+    write_env_file(file_name, &file, &file_policy(&read_repo_config()));
+}
+
+const DEFAULT_FILE_MODE: u32 = 0o600;
+// Recorded modes come from the server, so special bits and group/other write are never restored
+const RECORDED_MODE_MASK: u32 = 0o755;
+
+// `[permissions]` in .enva.toml. `mode` applies to fetched files whose mode wasn't recorded,
+// and to every fetched file with `policy = "enforce"`.
+pub struct FilePolicy {
+    mode: u32,
+    enforce: bool,
+}
+
+impl FilePolicy {
+    fn mode_for(&self, recorded: Option<u32>) -> u32 {
+        match recorded {
+            Some(mode) if !self.enforce => mode & RECORDED_MODE_MASK,
+            _ => self.mode,
+        }
+    }
+}
+
+pub fn file_policy(doc: &DocumentMut) -> FilePolicy {
+    let permissions = doc.get("permissions");
+
+    let mode = permissions
+        .and_then(|permissions| permissions.get("mode"))
+        .map(|mode| match (mode.as_str(), mode.as_integer()) {
+            (Some(mode), _) => u32::from_str_radix(mode.trim_start_matches("0o"), 8).ok(),
+            (_, Some(mode)) => u32::try_from(mode).ok(),
+            _ => None,
+        })
+        .map(|mode| mode.filter(|mode| *mode <= 0o7777).expect("Invalid mode under [permissions] in .enva.toml"))
+        .unwrap_or(DEFAULT_FILE_MODE);
+
+    let enforce = match permissions.and_then(|permissions| permissions.get("policy")).and_then(|policy| policy.as_str()) {
+        None | Some("preserve") => false,
+        Some("enforce") => true,
+        Some(policy) => panic!("Unknown permissions policy `{}` in .enva.toml, expected preserve or enforce", policy),
+    };
+
+    FilePolicy { mode, enforce }
+}
+
+// New files are created with the final mode, so a secret is never briefly readable under the umask default
+#[cfg(unix)]
+pub fn write_env_file(file_name: &str, file: &EnvFile, policy: &FilePolicy) {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mode = policy.mode_for(file.mode);
+
+    let mut handle = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(file_name)
+        .expect("Failed to open env file");
+
+    handle
+        .set_permissions(fs::Permissions::from_mode(mode))
+        .expect("Failed to set env file permissions");
+    handle.write_all(&file.content).expect("Failed to write env file");
+}
+
+#[cfg(not(unix))]
+pub fn write_env_file(file_name: &str, file: &EnvFile, _policy: &FilePolicy) {
+    fs::write(file_name, &file.content).expect("Failed to write env file");
+}
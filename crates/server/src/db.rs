@@ -1,71 +1,322 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use enva_shared::models::{BranchInfo, CommitInfo, CommitRequest, EnvFile, RekeySnapshot, RevisionInfo, RollbackRequest, SignedManifest};
 use log::info;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
 use toml_edit::DocumentMut;
 use toml_edit::de::from_document;
 use uuid::Uuid;
 
+const VALUES_MAC_LINE: &[u8] = b"\n# enva-mac: ";
+// AES-GCM nonce and tag around an empty plaintext
+const LEGACY_MIN_LEN: usize = 12 + 16;
+
+// Serializes read-modify-write cycles of db.toml between concurrent requests
+static DB_LOCK: Mutex<()> = Mutex::new(());
+
 #[derive(Deserialize, Serialize)]
 struct Database {
     #[serde(default)]
-    commits: HashMap<String, Commit>,
+    commits: HashMap<String, Commit>, // latest revision of each snapshot
+    #[serde(default)]
+    history: HashMap<String, Vec<Commit>>, // earlier revisions, oldest first
+    #[serde(default)]
+    branches: HashMap<String, BranchHead>, // owner/repo/branch: latest upload from the branch
+    #[serde(default)]
+    repos: HashMap<String, Repo>,
+    #[serde(default)]
+    replaced: HashMap<String, Vec<Commit>>, // owner/repo/commit: revisions a rekey replaced, under older keys
 }
 
 #[derive(Deserialize, Serialize)]
+struct BranchHead {
+    commit_id: String,
+    // Unknown for heads backfilled from snapshots stored before upload times
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Repo {
+    // Once set, the repo only accepts encrypted snapshots
+    #[serde(default)]
+    encrypted: bool,
+    key_check: Option<String>,
+    #[serde(default)]
+    key_generation: u32,
+    // Whether blobs from before the envelope format remain, None until they were scanned for
+    #[serde(default)]
+    legacy_blobs: Option<bool>,
+}
+
+impl Repo {
+    // Repos registered before the explicit flag are encrypted if they have a key check
+    fn is_encrypted(&self) -> bool {
+        self.encrypted || self.key_check.is_some()
+    }
+
+    fn settings(&self) -> RepoSettings {
+        RepoSettings {
+            encrypted: self.is_encrypted(),
+            key_check: self.key_check.clone(),
+            key_generation: self.key_generation,
+            legacy_blobs: self.legacy_blobs.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RepoSettings {
+    pub encrypted: bool,
+    pub key_check: Option<String>,
+    pub key_generation: u32,
+    pub legacy_blobs: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 struct Commit {
     branch: String,
     env_files_paths: HashMap<String, String>, // original file name: local file path
+    #[serde(default)]
+    manifest: Option<SignedManifest>,
+    #[serde(default)]
+    archive_path: Option<String>,
+    #[serde(default)]
+    modes: HashMap<String, u32>, // original file name: unix permission bits
+    // Bumped each time the same commit is uploaded again, snapshots stored before revisions are 1
+    #[serde(default = "first_revision")]
+    revision: u32,
+    #[serde(default)]
+    uploaded_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    uploader: Option<String>,
+    // Files uploaded in the pre-envelope format, the only ones clients decrypt without associated data.
+    // None until the commit was scanned for them.
+    #[serde(default)]
+    legacy_files: Option<BTreeSet<String>>,
 }
 
-pub fn save(
-    repo_url: &str,
-    branch: &str,
-    commit_id: &str,
-    env_files: &HashMap<String, String>,
-) -> Result<(), String> {
-    if let Some((owner, repo_name)) = enva_shared::parse_github_repo(repo_url) {
-        let id = format!("{}/{}/{}", owner, repo_name, commit_id);
+impl Commit {
+    fn revision_info(&self) -> RevisionInfo {
+        RevisionInfo {
+            revision: self.revision,
+            uploaded_at: self.uploaded_at,
+            uploader: self.uploader.clone(),
+            branch: Some(self.branch.clone()),
+        }
+    }
+}
 
-        let mut env_files_paths: HashMap<String, String> = HashMap::new();
+fn first_revision() -> u32 {
+    1
+}
 
-        for (key, value) in env_files {
-            let file_id = Uuid::new_v4().to_string();
+pub struct Snapshot {
+    pub env_files: HashMap<String, EnvFile>,
+    pub manifest: Option<SignedManifest>,
+    pub archive: Option<Vec<u8>>,
+    pub revision: u32,
+    pub legacy_blobs: Vec<String>,
+}
 
-            save_file(&file_id, value)?;
+pub fn save(request: &CommitRequest, uploader: Option<&str>) -> Result<u32, String> {
+    let repo_id = repo_id(&request.repo_url)?;
+    let id = format!("{}/{}", repo_id, request.commit_id);
+    let key_check = request.key_check.as_deref();
+    let archive = request.archive.as_deref();
+
+    let _lock = DB_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut db = load_database()?;
+
+    // Checked under the lock so a commit can't slip in with the old key during a rekey
+    if let Some(repo) = db.repos.get(&repo_id)
+        && repo.is_encrypted()
+    {
+        let plaintext = key_check.is_none()
+            || !request.env_files.values().all(|file| is_encrypted_blob(&file.content))
+            || archive.is_some_and(|archive| !is_encrypted_blob(archive));
+
+        if plaintext {
+            return Err("This repo is encrypted, refusing to store plaintext env files".to_string());
+        }
 
-            env_files_paths.insert(key.clone(), file_id);
+        if repo.key_check.as_deref().is_some_and(|expected| key_check != Some(expected)) {
+            return Err("Env files are encrypted with a different key than this repo uses".to_string());
         }
+    }
+
+    check_revision(&db, &id, request.revision)?;
+
+    let now = Utc::now();
+
+    let mut commit = store_commit(&request.branch, &request.env_files, request.manifest.as_ref(), archive)?;
+
+    // Old clients still upload blobs in the pre-envelope format
+    if commit.legacy_files.as_ref().is_some_and(|files| !files.is_empty()) {
+        db.repos.entry(repo_id.clone()).or_default().legacy_blobs = Some(true);
+    }
+
+    commit.uploaded_at = Some(now);
+    commit.uploader = uploader.map(str::to_string);
 
-        let commit = Commit {
-            branch: branch.to_string(),
-            env_files_paths,
+    // A detached HEAD isn't a branch anyone can fetch
+    if request.branch != "HEAD" {
+        let head = BranchHead {
+            commit_id: request.commit_id.clone(),
+            updated_at: Some(now),
         };
 
-        if let Some(config_dir) = enva_shared::get_config_dir() {
-            let db_path = config_dir.join("db.toml");
-            info!("DB path: {}", db_path.display());
+        db.branches.insert(format!("{}/{}", repo_id, request.branch), head);
+    }
 
-            std::fs::create_dir_all(&config_dir).expect("Failed to create config directory");
+    let revision = add_revision(&mut db, id, commit);
+    save_database(&db)?;
 
-            let text = std::fs::read_to_string(&db_path).unwrap_or_else(|_| String::new());
+    Ok(revision)
+}
 
-            let doc = text.parse::<DocumentMut>().map_err(|e| e.to_string())?;
+// The client signs the revision it expects to create, so a concurrent upload must not take it
+fn check_revision(db: &Database, id: &str, expected: Option<u32>) -> Result<(), String> {
+    let next = db.commits.get(id).map_or(1, |latest| latest.revision + 1);
 
-            let mut db: Database = from_document(doc.clone()).map_err(|e| e.to_string())?;
+    match expected {
+        Some(expected) if expected != next => Err(format!(
+            "Revision {} of {} was signed, but the next revision is {}, another upload got there first",
+            expected, id, next
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Makes `commit` the latest revision of `id`, the previous one stays readable in the history
+fn add_revision(db: &mut Database, id: String, mut commit: Commit) -> u32 {
+    if let Some(previous) = db.commits.remove(&id) {
+        commit.revision = previous.revision + 1;
+        db.history.entry(id.clone()).or_default().push(previous);
+    }
 
-            db.commits.insert(id.clone(), commit);
+    let revision = commit.revision;
+    db.commits.insert(id, commit);
 
-            let updated_toml = toml::to_string(&db).map_err(|e| e.to_string())?;
-            std::fs::write(&db_path, updated_toml).map_err(|e| e.to_string())?;
+    revision
+}
 
-            return Ok(());
+// Copies an earlier revision as the new latest one, keeping everything in between. The copy
+// carries the manifest the client signed for its new revision.
+pub fn rollback(request: &RollbackRequest, uploader: Option<&str>) -> Result<u32, String> {
+    let repo_id = repo_id(&request.repo_url)?;
+    let id = format!("{}/{}", repo_id, request.commit_id);
+
+    let _lock = DB_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut db = load_database()?;
+
+    let target = db
+        .commits
+        .get(&id)
+        .into_iter()
+        .chain(db.history.get(&id).into_iter().flatten())
+        .find(|commit| commit.revision == request.revision)
+        .cloned()
+        .ok_or_else(|| format!("Revision {} of commit {} not found", request.revision, id))?;
+
+    // Revisions stored before encryption was turned on must not become the latest again
+    if db.repos.get(&repo_id).is_some_and(Repo::is_encrypted) {
+        let encrypted = target
+            .env_files_paths
+            .values()
+            .chain(&target.archive_path)
+            .map(|file_id| read_file(file_id).map(|content| is_encrypted_blob(&content)))
+            .collect::<Result<Vec<_>, String>>()?
+            .into_iter()
+            .all(|encrypted| encrypted);
+
+        if !encrypted {
+            return Err("This repo is encrypted, refusing to restore a plaintext revision".to_string());
         }
     }
 
-    Err(format!("Failed to parse repo URL: {}", repo_url))
+    check_revision(&db, &id, request.new_revision)?;
+
+    let commit = Commit {
+        manifest: request.manifest.clone(),
+        uploaded_at: Some(Utc::now()),
+        uploader: uploader.map(str::to_string),
+        ..target
+    };
+
+    let revision = add_revision(&mut db, id, commit);
+    save_database(&db)?;
+
+    Ok(revision)
+}
+
+// Pre-envelope blobs are base64 of nonce || ciphertext || tag, which plaintext env files never
+// are in practice
+fn is_legacy_blob(content: &[u8]) -> bool {
+    !is_encrypted_blob(content) && STANDARD.decode(content).is_ok_and(|data| data.len() >= LEGACY_MIN_LEN)
+}
+
+// Encrypted blobs are an envelope, raw or base64-encoded by older clients, or a dotenv
+// file with encrypted values and a MAC line
+fn is_encrypted_blob(content: &[u8]) -> bool {
+    content.starts_with(b"ENVA")
+        || content.windows(VALUES_MAC_LINE.len()).any(|window| window == VALUES_MAC_LINE)
+        || STANDARD
+            .decode(content)
+            .is_ok_and(|data| data.starts_with(b"ENVA"))
 }
 
-fn save_file(file_id: &str, content: &str) -> Result<(), String> {
+fn store_commit(
+    branch: &str,
+    env_files: &HashMap<String, EnvFile>,
+    manifest: Option<&SignedManifest>,
+    archive: Option<&[u8]>,
+) -> Result<Commit, String> {
+    let mut env_files_paths: HashMap<String, String> = HashMap::new();
+    let mut modes: HashMap<String, u32> = HashMap::new();
+    let mut legacy_files = BTreeSet::new();
+
+    for (key, file) in env_files {
+        let file_id = Uuid::new_v4().to_string();
+
+        save_file(&file_id, &file.content)?;
+
+        env_files_paths.insert(key.clone(), file_id);
+
+        if let Some(mode) = file.mode {
+            modes.insert(key.clone(), mode);
+        }
+
+        if is_legacy_blob(&file.content) {
+            legacy_files.insert(key.clone());
+        }
+    }
+
+    let archive_path = match archive {
+        Some(archive) => {
+            let file_id = Uuid::new_v4().to_string();
+            save_file(&file_id, archive)?;
+            Some(file_id)
+        }
+        None => None,
+    };
+
+    Ok(Commit {
+        branch: branch.to_string(),
+        env_files_paths,
+        manifest: manifest.cloned(),
+        archive_path,
+        modes,
+        revision: first_revision(),
+        uploaded_at: None,
+        uploader: None,
+        legacy_files: Some(legacy_files),
+    })
+}
+
+fn save_file(file_id: &str, content: &[u8]) -> Result<(), String> {
     if let Some(config_dir) = enva_shared::get_config_dir() {
         let envs_dir = config_dir.join("envs");
         info!("Env dir: {}", envs_dir.display());
@@ -81,10 +332,12 @@ fn save_file(file_id: &str, content: &str) -> Result<(), String> {
     Err(format!("Failed to save file: {}", file_id))
 }
 
+// Reads the latest revision of a snapshot, or the given one
 pub fn read(
     repo_url: &str,
     commit_id: &str,
-) -> Result<HashMap<String, String>, String> {
+    revision: Option<u32>,
+) -> Result<Snapshot, String> {
     if let Some((owner, repo_name)) = enva_shared::parse_github_repo(repo_url) {
         let id = format!("{}/{}/{}", owner, repo_name, commit_id);
 
@@ -97,63 +350,401 @@ pub fn read(
 
             let db: Database = from_document(doc.clone()).map_err(|e| e.to_string())?;
 
-            let env_files = db
+            let latest = db
                 .commits
                 .get(&id)
-                .ok_or_else(|| format!("Commit {} not found", id))?
+                .ok_or_else(|| format!("Commit {} not found", id))?;
+
+            let commit = match revision {
+                Some(revision) if revision != latest.revision => db
+                    .history
+                    .get(&id)
+                    .and_then(|history| history.iter().find(|commit| commit.revision == revision))
+                    .ok_or_else(|| format!("Revision {} of commit {} not found", revision, id))?,
+                _ => latest,
+            };
+
+            let env_files = commit
                 .env_files_paths
                 .iter()
                 .map(|(name, file_id)| {
-                    // Return Result<(String, String), String>
-                    read_file(file_id).map(|content| (name.clone(), content))
+                    read_file(file_id).map(|content| {
+                        let file = EnvFile {
+                            content,
+                            mode: commit.modes.get(name).copied(),
+                        };
+
+                        (name.clone(), file)
+                    })
                 })
-                .collect::<Result<HashMap<String, String>, String>>()?;
+                .collect::<Result<HashMap<String, EnvFile>, String>>()?;
 
-            return Ok(env_files);
+            let archive = commit.archive_path.as_deref().map(read_file).transpose()?;
+
+            return Ok(Snapshot {
+                env_files,
+                manifest: commit.manifest.clone(),
+                archive,
+                revision: commit.revision,
+                legacy_blobs: commit.legacy_files.iter().flatten().cloned().collect(),
+            });
         }
     }
 
     Err(format!("Failed to parse repo URL: {}", repo_url))
 }
 
-fn read_file(file_id: &str) -> Result<String, String> {
+fn read_file(file_id: &str) -> Result<Vec<u8>, String> {
     if let Some(config_dir) = enva_shared::get_config_dir() {
         let envs_dir = config_dir.join("envs");
 
         let env_file_path = envs_dir.join(file_id);
-        return Ok(std::fs::read_to_string(&env_file_path)
-            .map_err(|e| e.to_string())?
-            .to_string());
+        return std::fs::read(&env_file_path).map_err(|e| e.to_string());
     }
 
     Err(format!("Failed to read file: {}", file_id))
 }
 
-pub fn exists(repo_url: &str, commit_id: &str) -> Result<bool, String> {
+pub fn latest_revision(repo_url: &str, commit_id: &str) -> Result<Option<u32>, String> {
     if let Some((owner, repo_name)) = enva_shared::parse_github_repo(repo_url) {
         let id = format!("{}/{}/{}", owner, repo_name, commit_id);
 
         if let Some(config_dir) = enva_shared::get_config_dir() {
             let db_path = config_dir.join("db.toml");
 
-            // If the database file doesn't exist, return false
+            // If the database file doesn't exist, there is no snapshot
             if !db_path.exists() {
-                return Ok(false);
+                return Ok(None);
             }
 
             let text = std::fs::read_to_string(&db_path).unwrap_or_else(|_| String::new());
 
-            // If file is empty, return false
+            // If file is empty, there is no snapshot
             if text.is_empty() {
-                return Ok(false);
+                return Ok(None);
             }
 
             let doc = text.parse::<DocumentMut>().map_err(|e| e.to_string())?;
             let db: Database = from_document(doc.clone()).map_err(|e| e.to_string())?;
 
-            return Ok(db.commits.contains_key(&id));
+            return Ok(db.commits.get(&id).map(|commit| commit.revision));
         }
     }
 
     Err(format!("Failed to parse repo URL: {}", repo_url))
 }
+
+pub fn list(repo_url: &str) -> Result<Vec<CommitInfo>, String> {
+    if let Some((owner, repo_name)) = enva_shared::parse_github_repo(repo_url) {
+        let prefix = format!("{}/{}/", owner, repo_name);
+
+        if let Some(config_dir) = enva_shared::get_config_dir() {
+            let db_path = config_dir.join("db.toml");
+
+            let text = std::fs::read_to_string(&db_path).unwrap_or_else(|_| String::new());
+
+            let doc = text.parse::<DocumentMut>().map_err(|e| e.to_string())?;
+            let db: Database = from_document(doc.clone()).map_err(|e| e.to_string())?;
+
+            let commits = db
+                .commits
+                .iter()
+                .filter_map(|(id, commit)| {
+                    id.strip_prefix(&prefix).map(|commit_id| CommitInfo {
+                        commit_id: commit_id.to_string(),
+                        branch: commit.branch.clone(),
+                        manifest: commit.manifest.clone(),
+                        revisions: db
+                            .history
+                            .get(id)
+                            .into_iter()
+                            .flatten()
+                            .chain([commit])
+                            .map(Commit::revision_info)
+                            .collect(),
+                    })
+                })
+                .collect();
+
+            return Ok(commits);
+        }
+    }
+
+    Err(format!("Failed to parse repo URL: {}", repo_url))
+}
+
+pub fn branches(repo_url: &str) -> Result<Vec<BranchInfo>, String> {
+    let prefix = format!("{}/", repo_id(repo_url)?);
+    let db = load_database()?;
+
+    let mut branches: Vec<BranchInfo> = db
+        .branches
+        .into_iter()
+        .filter_map(|(id, head)| {
+            id.strip_prefix(&prefix).map(|branch| BranchInfo {
+                branch: branch.to_string(),
+                commit_id: head.commit_id,
+                updated_at: head.updated_at,
+            })
+        })
+        .collect();
+
+    branches.sort_by(|a, b| a.branch.cmp(&b.branch));
+
+    Ok(branches)
+}
+
+fn load_database() -> Result<Database, String> {
+    let config_dir = enva_shared::get_config_dir().ok_or("Failed to get config directory")?;
+    let db_path = config_dir.join("db.toml");
+
+    let text = std::fs::read_to_string(&db_path).unwrap_or_else(|_| String::new());
+
+    let doc = text.parse::<DocumentMut>().map_err(|e| e.to_string())?;
+    let mut db: Database = from_document(doc).map_err(|e| e.to_string())?;
+
+    backfill_branches(&mut db);
+
+    Ok(db)
+}
+
+// Snapshots stored before the branch index only record their branch, so branches without a
+// head get their most recently uploaded snapshot. Snapshots without an upload time can't be
+// ordered and lose to any that have one, ties go to the highest commit id to stay stable.
+fn backfill_branches(db: &mut Database) {
+    let mut heads: HashMap<String, (&Option<DateTime<Utc>>, &str)> = HashMap::new();
+
+    for (id, commit) in &db.commits {
+        let Some((repo_id, commit_id)) = id.rsplit_once('/') else {
+            continue;
+        };
+
+        let key = format!("{}/{}", repo_id, commit.branch);
+
+        if commit.branch == "HEAD" || db.branches.contains_key(&key) {
+            continue;
+        }
+
+        let candidate = (&commit.uploaded_at, commit_id);
+
+        if heads.get(&key).is_none_or(|head| candidate > *head) {
+            heads.insert(key, candidate);
+        }
+    }
+
+    let heads: Vec<(String, BranchHead)> = heads
+        .into_iter()
+        .map(|(key, (updated_at, commit_id))| {
+            let head = BranchHead {
+                commit_id: commit_id.to_string(),
+                updated_at: *updated_at,
+            };
+
+            (key, head)
+        })
+        .collect();
+
+    db.branches.extend(heads);
+}
+
+fn save_database(db: &Database) -> Result<(), String> {
+    let config_dir = enva_shared::get_config_dir().ok_or("Failed to get config directory")?;
+    let db_path = config_dir.join("db.toml");
+
+    std::fs::create_dir_all(&config_dir).map_err(|e| e.to_string())?;
+
+    // Written to a temporary file and renamed, so a crash never leaves a half-written database
+    let tmp_path = config_dir.join("db.toml.tmp");
+
+    let updated_toml = toml::to_string(db).map_err(|e| e.to_string())?;
+    std::fs::write(&tmp_path, updated_toml).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp_path, &db_path).map_err(|e| e.to_string())
+}
+
+fn repo_id(repo_url: &str) -> Result<String, String> {
+    enva_shared::parse_github_repo(repo_url)
+        .map(|(owner, repo_name)| format!("{}/{}", owner, repo_name))
+        .ok_or_else(|| format!("Failed to parse repo URL: {}", repo_url))
+}
+
+pub fn get_settings(repo_url: &str) -> Result<RepoSettings, String> {
+    let id = repo_id(repo_url)?;
+
+    let _lock = DB_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut db = load_database()?;
+
+    scan_legacy_blobs(&mut db, &id)?;
+
+    Ok(db.repos.get(&id).map(Repo::settings).unwrap_or_default())
+}
+
+// Records the pre-envelope blobs of commits stored before they were tracked, once per commit.
+// Later uploads and rekeys keep the lists and the repo flag current.
+fn scan_legacy_blobs(db: &mut Database, repo_id: &str) -> Result<(), String> {
+    let prefix = format!("{}/", repo_id);
+
+    let unscanned = db
+        .commits
+        .iter()
+        .chain(db.history.iter().flat_map(|(id, history)| history.iter().map(move |commit| (id, commit))))
+        .any(|(id, commit)| id.starts_with(&prefix) && commit.legacy_files.is_none());
+
+    if !unscanned && db.repos.get(repo_id).is_some_and(|repo| repo.legacy_blobs.is_some()) {
+        return Ok(());
+    }
+
+    let mut legacy = false;
+
+    for commit in db
+        .commits
+        .iter_mut()
+        .chain(db.history.iter_mut().flat_map(|(id, history)| history.iter_mut().map(move |commit| (&*id, commit))))
+        .filter(|(id, _)| id.starts_with(&prefix))
+        .map(|(_, commit)| commit)
+    {
+        if commit.legacy_files.is_none() {
+            let mut files = BTreeSet::new();
+
+            for (name, file_id) in &commit.env_files_paths {
+                if is_legacy_blob(&read_file(file_id)?) {
+                    files.insert(name.clone());
+                }
+            }
+
+            commit.legacy_files = Some(files);
+        }
+
+        legacy |= commit.legacy_files.as_ref().is_some_and(|files| !files.is_empty());
+    }
+
+    db.repos.entry(repo_id.to_string()).or_default().legacy_blobs = Some(legacy);
+
+    save_database(db)
+}
+
+// Registers the key check if the repo has none yet, turning encryption on for good,
+// and returns the settings in use
+pub fn set_key_check(repo_url: &str, key_check: &str) -> Result<RepoSettings, String> {
+    let id = repo_id(repo_url)?;
+
+    let _lock = DB_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut db = load_database()?;
+
+    scan_legacy_blobs(&mut db, &id)?;
+
+    let repo = db.repos.entry(id).or_default();
+
+    if repo.key_check.is_none() {
+        repo.encrypted = true;
+        repo.key_check = Some(key_check.to_string());
+        let settings = repo.settings();
+
+        save_database(&db)?;
+
+        return Ok(settings);
+    }
+
+    Ok(repo.settings())
+}
+
+// Replaces every revision of every snapshot of the repo and its key check in a single database write.
+// The replaced revisions are kept as they were, so the old blobs remain for recovery.
+pub fn rekey(
+    repo_url: &str,
+    old_key_check: Option<&str>,
+    key_check: &str,
+    key_generation: u32,
+    snapshots: &[RekeySnapshot],
+) -> Result<(), String> {
+    let repo_id = repo_id(repo_url)?;
+    let prefix = format!("{}/", repo_id);
+
+    let _lock = DB_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut db = load_database()?;
+
+    let repo = db.repos.get(&repo_id);
+
+    if repo.and_then(|repo| repo.key_check.as_deref()) != old_key_check {
+        return Err("The repo key changed while rekeying, fetch and try again".to_string());
+    }
+
+    let current_generation = repo.map(|repo| repo.key_generation).unwrap_or_default();
+
+    if key_generation != current_generation + 1 {
+        return Err(format!(
+            "Expected key generation {}, got {}",
+            current_generation + 1,
+            key_generation
+        ));
+    }
+
+    let existing: BTreeSet<(&str, u32)> = db
+        .commits
+        .iter()
+        .chain(db.history.iter().flat_map(|(id, history)| history.iter().map(move |commit| (id, commit))))
+        .filter_map(|(id, commit)| id.strip_prefix(&prefix).map(|commit_id| (commit_id, commit.revision)))
+        .collect();
+    let rekeyed: BTreeSet<(&str, u32)> = snapshots.iter().map(|s| (s.commit_id.as_str(), s.revision)).collect();
+
+    if existing != rekeyed {
+        return Err("Snapshots were added while rekeying, try again".to_string());
+    }
+
+    let mut rekeyed_commits: HashMap<String, Vec<Commit>> = HashMap::new();
+
+    for snapshot in snapshots {
+        let mut commit = store_commit(
+            &snapshot.branch,
+            &snapshot.env_files,
+            snapshot.manifest.as_ref(),
+            snapshot.archive.as_deref(),
+        )?;
+        commit.revision = snapshot.revision;
+
+        rekeyed_commits
+            .entry(format!("{}{}", prefix, snapshot.commit_id))
+            .or_default()
+            .push(commit);
+    }
+
+    for (id, mut revisions) in rekeyed_commits {
+        // Re-encrypting doesn't change when or by whom a revision was uploaded
+        for commit in &mut revisions {
+            if let Some(old) = db
+                .commits
+                .get(&id)
+                .into_iter()
+                .chain(db.history.get(&id).into_iter().flatten())
+                .find(|old| old.revision == commit.revision)
+            {
+                commit.uploaded_at = old.uploaded_at;
+                commit.uploader = old.uploader.clone();
+            }
+        }
+
+        revisions.sort_by_key(|commit| commit.revision);
+        let latest = revisions.pop().expect("Every rekeyed commit has a revision");
+
+        let old = db
+            .commits
+            .insert(id.clone(), latest)
+            .into_iter()
+            .chain(db.history.remove(&id).into_iter().flatten());
+
+        // The old blobs stay on disk and in the database, so a bad rekey can't destroy any revision
+        db.replaced.entry(id.clone()).or_default().extend(old);
+
+        if !revisions.is_empty() {
+            db.history.insert(id, revisions);
+        }
+    }
+
+    let repo = db.repos.entry(repo_id).or_default();
+    repo.encrypted = true;
+    repo.key_check = Some(key_check.to_string());
+    repo.key_generation = key_generation;
+    // Every blob was re-encrypted into the current envelope
+    repo.legacy_blobs = Some(false);
+
+    save_database(&db)
+}
@@ -1,8 +1,8 @@
 use crate::db;
-use enva_shared::models::{CommitRequest, CommitResponse, FetchRequest, FetchResponse, CheckCommitRequest, CheckCommitResponse};
+use enva_shared::models::{CommitRequest, CommitResponse, FetchRequest, FetchResponse, CheckCommitRequest, CheckCommitResponse, ListRequest, ListResponse, BranchesRequest, BranchesResponse, KeyCheckRequest, KeyCheckResponse, RekeyRequest, RekeyResponse, RollbackRequest, RollbackResponse};
 use axum::Json;
 use axum::http::{HeaderMap};
-use enva_shared::check_ownership;
+use enva_shared::{check_ownership, check_push_access};
 
 pub async fn commit(
     headers: HeaderMap,
@@ -17,43 +17,35 @@ pub async fn commit(
     if auth_token.is_empty() {
         return Json(CommitResponse {
             success: false,
+            revision: None,
             error: Some("No token provided".into()),
         });
     }
 
-    match check_ownership(auth_token, &request.repo_url).await {
-        Ok(status) => {
-            if !status {
-                return Json(CommitResponse {
-                    success: false,
-                    error: Some("You don't have ownership of this repository".into()),
-                });
-            }
-        }
+    let uploader = match check_ownership(auth_token, &request.repo_url).await {
+        Ok(login) => login,
         Err(err) => {
             return Json(CommitResponse {
                 success: false,
+                revision: None,
                 error: Some(err),
             });
         }
-    }
+    };
 
-    db::save(
-        &request.repo_url,
-        &request.branch,
-        &request.commit_id,
-        &request.env_files,
-    )
+    db::save(&request, Some(&uploader))
     .map_or_else(
         |e| {
             Json(CommitResponse {
                 success: false,
+                revision: None,
                 error: e.into(),
             })
         },
-        |_| {
+        |revision| {
             Json(CommitResponse {
                 success: true,
+                revision: Some(revision),
                 error: None,
             })
         },
@@ -74,34 +66,38 @@ pub async fn fetch(
         return Json(FetchResponse {
             success: false,
             env_files: None,
+            manifest: None,
+            archive: None,
+            revision: None,
+            legacy_blobs: Vec::new(),
             error: Some("No token provided".into()),
         });
     }
 
     match check_ownership(auth_token, &request.repo_url).await {
-        Ok(status) => {
-            if !status {
-                return Json(FetchResponse {
-                    success: false,
-                    env_files: None,
-                    error: Some("You don't have ownership of this repository".into()),
-                });
-            }
-        }
+        Ok(_) => {}
         Err(err) => {
             return Json(FetchResponse {
                 success: false,
                 env_files: None,
+                manifest: None,
+                archive: None,
+                revision: None,
+                legacy_blobs: Vec::new(),
                 error: Some(err),
             });
         }
     }
 
-    match db::read(&request.repo_url, &request.commit_id) {
-        Ok(env_files) => {
+    match db::read(&request.repo_url, &request.commit_id, request.revision) {
+        Ok(snapshot) => {
             Json(FetchResponse {
                 success: true,
-                env_files: Some(env_files),
+                env_files: Some(snapshot.env_files),
+                manifest: snapshot.manifest,
+                archive: snapshot.archive,
+                revision: Some(snapshot.revision),
+                legacy_blobs: snapshot.legacy_blobs,
                 error: None,
             })
         }
@@ -109,6 +105,10 @@ pub async fn fetch(
             Json(FetchResponse {
                 success: false,
                 env_files: None,
+                manifest: None,
+                archive: None,
+                revision: None,
+                legacy_blobs: Vec::new(),
                 error: Some(err),
             })
         }
@@ -128,37 +128,289 @@ pub async fn check_commit(
     if auth_token.is_empty() {
         return Json(CheckCommitResponse {
             exists: false,
+            revision: None,
             error: Some("No token provided".into()),
         });
     }
 
     match check_ownership(auth_token, &request.repo_url).await {
-        Ok(status) => {
-            if !status {
-                return Json(CheckCommitResponse {
-                    exists: false,
-                    error: Some("You don't have ownership of this repository".into()),
-                });
-            }
-        }
+        Ok(_) => {}
         Err(err) => {
             return Json(CheckCommitResponse {
                 exists: false,
+                revision: None,
                 error: Some(err),
             });
         }
     }
 
-    match db::exists(&request.repo_url, &request.commit_id) {
-        Ok(exists) => {
+    match db::latest_revision(&request.repo_url, &request.commit_id) {
+        Ok(revision) => {
             Json(CheckCommitResponse {
-                exists,
+                exists: revision.is_some(),
+                revision,
                 error: None,
             })
         }
         Err(err) => {
             Json(CheckCommitResponse {
                 exists: false,
+                revision: None,
+                error: Some(err),
+            })
+        }
+    }
+}
+
+pub async fn list(
+    headers: HeaderMap,
+    Json(request): Json<ListRequest>,
+) -> Json<ListResponse> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if auth_token.is_empty() {
+        return Json(ListResponse {
+            success: false,
+            commits: None,
+            error: Some("No token provided".into()),
+        });
+    }
+
+    match check_ownership(auth_token, &request.repo_url).await {
+        Ok(_) => {}
+        Err(err) => {
+            return Json(ListResponse {
+                success: false,
+                commits: None,
+                error: Some(err),
+            });
+        }
+    }
+
+    match db::list(&request.repo_url) {
+        Ok(commits) => {
+            Json(ListResponse {
+                success: true,
+                commits: Some(commits),
+                error: None,
+            })
+        }
+        Err(err) => {
+            Json(ListResponse {
+                success: false,
+                commits: None,
+                error: Some(err),
+            })
+        }
+    }
+}
+
+pub async fn branches(
+    headers: HeaderMap,
+    Json(request): Json<BranchesRequest>,
+) -> Json<BranchesResponse> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if auth_token.is_empty() {
+        return Json(BranchesResponse {
+            success: false,
+            branches: None,
+            error: Some("No token provided".into()),
+        });
+    }
+
+    match check_ownership(auth_token, &request.repo_url).await {
+        Ok(_) => {}
+        Err(err) => {
+            return Json(BranchesResponse {
+                success: false,
+                branches: None,
+                error: Some(err),
+            });
+        }
+    }
+
+    match db::branches(&request.repo_url) {
+        Ok(branches) => {
+            Json(BranchesResponse {
+                success: true,
+                branches: Some(branches),
+                error: None,
+            })
+        }
+        Err(err) => {
+            Json(BranchesResponse {
+                success: false,
+                branches: None,
+                error: Some(err),
+            })
+        }
+    }
+}
+
+pub async fn key_check(
+    headers: HeaderMap,
+    Json(request): Json<KeyCheckRequest>,
+) -> Json<KeyCheckResponse> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if auth_token.is_empty() {
+        return Json(KeyCheckResponse {
+            success: false,
+            encrypted: false,
+            key_check: None,
+            key_generation: 0,
+            legacy_blobs: false,
+            error: Some("No token provided".into()),
+        });
+    }
+
+    match check_ownership(auth_token, &request.repo_url).await {
+        Ok(_) => {}
+        Err(err) => {
+            return Json(KeyCheckResponse {
+                success: false,
+                encrypted: false,
+                key_check: None,
+                key_generation: 0,
+                legacy_blobs: false,
+                error: Some(err),
+            });
+        }
+    }
+
+    let result = match &request.key_check {
+        Some(key_check) => db::set_key_check(&request.repo_url, key_check),
+        None => db::get_settings(&request.repo_url),
+    };
+
+    match result {
+        Ok(settings) => {
+            Json(KeyCheckResponse {
+                success: true,
+                encrypted: settings.encrypted,
+                key_check: settings.key_check,
+                key_generation: settings.key_generation,
+                legacy_blobs: settings.legacy_blobs,
+                error: None,
+            })
+        }
+        Err(err) => {
+            Json(KeyCheckResponse {
+                success: false,
+                encrypted: false,
+                key_check: None,
+                key_generation: 0,
+                legacy_blobs: false,
+                error: Some(err),
+            })
+        }
+    }
+}
+
+pub async fn rekey(
+    headers: HeaderMap,
+    Json(request): Json<RekeyRequest>,
+) -> Json<RekeyResponse> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if auth_token.is_empty() {
+        return Json(RekeyResponse {
+            success: false,
+            error: Some("No token provided".into()),
+        });
+    }
+
+    // A rekey replaces every stored blob, so read-only members who can fetch the key check can't run one
+    match check_push_access(auth_token, &request.repo_url).await {
+        Ok(_) => {}
+        Err(err) => {
+            return Json(RekeyResponse {
+                success: false,
+                error: Some(err),
+            });
+        }
+    }
+
+    match db::rekey(
+        &request.repo_url,
+        request.old_key_check.as_deref(),
+        &request.key_check,
+        request.key_generation,
+        &request.snapshots,
+    ) {
+        Ok(()) => {
+            Json(RekeyResponse {
+                success: true,
+                error: None,
+            })
+        }
+        Err(err) => {
+            Json(RekeyResponse {
+                success: false,
+                error: Some(err),
+            })
+        }
+    }
+}
+
+pub async fn rollback(
+    headers: HeaderMap,
+    Json(request): Json<RollbackRequest>,
+) -> Json<RollbackResponse> {
+    let auth_token = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if auth_token.is_empty() {
+        return Json(RollbackResponse {
+            success: false,
+            revision: None,
+            error: Some("No token provided".into()),
+        });
+    }
+
+    let uploader = match check_ownership(auth_token, &request.repo_url).await {
+        Ok(login) => login,
+        Err(err) => {
+            return Json(RollbackResponse {
+                success: false,
+                revision: None,
+                error: Some(err),
+            });
+        }
+    };
+
+    match db::rollback(&request, Some(&uploader)) {
+        Ok(revision) => {
+            Json(RollbackResponse {
+                success: true,
+                revision: Some(revision),
+                error: None,
+            })
+        }
+        Err(err) => {
+            Json(RollbackResponse {
+                success: false,
+                revision: None,
                 error: Some(err),
             })
         }
@@ -14,7 +14,12 @@ async fn main() {
         .route("/", get(|| async { "OK" }))
         .route("/commit", post(handlers::commit))
         .route("/fetch", post(handlers::fetch))
-        .route("/check", post(handlers::check_commit));
+        .route("/check", post(handlers::check_commit))
+        .route("/list", post(handlers::list))
+        .route("/branches", post(handlers::branches))
+        .route("/key-check", post(handlers::key_check))
+        .route("/rekey", post(handlers::rekey))
+        .route("/rollback", post(handlers::rollback));
 
     let addr = SocketAddr::from((
         [0, 0, 0, 0],
@@ -0,0 +1,582 @@
+// Dotenv files as a list of lines, so values can be read and rewritten while comments,
+// blank lines, ordering and quoting of untouched entries survive byte for byte.
+//
+// Supported syntax:
+//   KEY=value                 unquoted, trailing whitespace and ` # comments` are dropped
+//   export KEY=value          `export` prefix
+//   KEY='literal $value'      single quotes, no escapes or interpolation, may span lines
+//   KEY="line\nnext ${VAR}"   double quotes with \n \r \t \" \\ \$ escapes, may span lines
+//   KEY=${VAR} $VAR ${VAR:-default}
+//   KEY=price$$5              `$$` is a literal `$`, unquoted or in double quotes
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default)]
+pub struct Dotenv {
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    // Blank lines, comments and anything that isn't an assignment, newline included
+    Text(String),
+    Entry(Entry),
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    prefix: String, // leading whitespace, `export`, the key and `=` as written
+    key: String,
+    raw_value: String, // everything after `=` up to the line break, quotes and comments included
+    parts: Vec<Part>,
+    newline: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Var { name: String, default: Option<String> },
+}
+
+impl Dotenv {
+    pub fn parse(content: &str) -> Result<Dotenv, String> {
+        let mut lines = Vec::new();
+        let mut rest = content;
+        let mut line_number = 1;
+
+        while !rest.is_empty() {
+            let line_end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+
+            let Some((prefix_len, key)) = parse_head(&rest[..line_end]) else {
+                lines.push(Line::Text(rest[..line_end].to_string()));
+                rest = &rest[line_end..];
+                line_number += 1;
+                continue;
+            };
+
+            let after = &rest[prefix_len..];
+            let (parts, _, value_len) = parse_value(after).map_err(|e| format!("Line {}: {}", line_number, e))?;
+
+            let remaining = &after[value_len..];
+            let newline_len = if remaining.starts_with("\r\n") {
+                2
+            } else {
+                usize::from(remaining.starts_with('\n'))
+            };
+
+            let entry_len = prefix_len + value_len + newline_len;
+            line_number += rest[..entry_len].matches('\n').count();
+
+            lines.push(Line::Entry(Entry {
+                prefix: rest[..prefix_len].to_string(),
+                key: key.to_string(),
+                raw_value: after[..value_len].to_string(),
+                parts,
+                newline: remaining[..newline_len].to_string(),
+            }));
+
+            rest = &rest[entry_len..];
+        }
+
+        Ok(Dotenv { lines })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Text(_) => None,
+        })
+    }
+
+    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut Entry> {
+        self.lines.iter_mut().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Text(_) => None,
+        })
+    }
+
+    // The uninterpolated value of the last assignment to `key`
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.entries().filter(|entry| entry.key == key).last().map(Entry::value)
+    }
+
+    // Rewrites the last assignment to `key` in place, keeping its trailing comment, or appends
+    // one. The value is stored literally, so `$` in it is never interpolated.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let raw_value = quote(value);
+        let parts = vec![Part::Text(value.to_string())];
+
+        if let Some(entry) = self.entries_mut().filter(|entry| entry.key == key).last() {
+            let comment = parse_value(&entry.raw_value).map_or("", |(_, end, _)| &entry.raw_value[end..]);
+            entry.raw_value = raw_value + comment;
+            entry.parts = parts;
+            return;
+        }
+
+        if let Some(last) = self.lines.last_mut() {
+            match last {
+                Line::Text(text) if !text.ends_with('\n') => text.push('\n'),
+                Line::Entry(entry) if entry.newline.is_empty() => entry.newline.push('\n'),
+                _ => {}
+            }
+        }
+
+        self.lines.push(Line::Entry(Entry {
+            prefix: format!("{key}="),
+            key: key.to_string(),
+            raw_value,
+            parts,
+            newline: "\n".to_string(),
+        }));
+    }
+
+    // Removes every assignment to `key`, returning whether there was one
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.lines.len();
+        self.lines.retain(|line| !matches!(line, Line::Entry(entry) if entry.key == key));
+        self.lines.len() != len
+    }
+
+    // Interpolates every value in order. A reference resolves to an earlier assignment in this
+    // file, then to `lookup`, then to its default or an empty string.
+    pub fn resolve(&self, lookup: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+        let mut resolved: HashMap<&str, String> = HashMap::new();
+        let mut vars = Vec::new();
+
+        for entry in self.entries() {
+            let value: String = entry
+                .parts
+                .iter()
+                .map(|part| match part {
+                    Part::Text(text) => text.clone(),
+                    Part::Var { name, default } => resolved
+                        .get(name.as_str())
+                        .cloned()
+                        .or_else(|| lookup(name))
+                        .or_else(|| default.clone())
+                        .unwrap_or_default(),
+                })
+                .collect();
+
+            resolved.insert(&entry.key, value.clone());
+            vars.push((entry.key.clone(), value));
+        }
+
+        vars
+    }
+}
+
+impl fmt::Display for Dotenv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Text(text) => f.write_str(text)?,
+                Line::Entry(entry) => write!(f, "{}{}{}", entry.prefix, entry.raw_value, entry.newline)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Entry {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // The value with escapes applied and references written back as `${VAR}`
+    pub fn value(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Var { name, default: None } => format!("${{{name}}}"),
+                Part::Var { name, default: Some(default) } => format!("${{{name}:-{default}}}"),
+            })
+            .collect()
+    }
+
+    pub fn raw_value(&self) -> &str {
+        &self.raw_value
+    }
+
+    // Replaces the value text as written, which must be a complete dotenv value
+    pub fn set_raw_value(&mut self, raw_value: &str) -> Result<(), String> {
+        let (parts, _, len) = parse_value(raw_value)?;
+
+        if len != raw_value.len() {
+            return Err(format!("Unexpected text after the value of {}", self.key));
+        }
+
+        self.raw_value = raw_value.to_string();
+        self.parts = parts;
+        Ok(())
+    }
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.starts_with(|c: char| c.is_ascii_digit()) && key.chars().all(is_key_char)
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+// Returns the length of `[ws][export ]KEY[ws]=` and the key, or None when the line isn't an assignment
+fn parse_head(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start();
+
+    let body = trimmed
+        .strip_prefix("export")
+        .filter(|rest| rest.starts_with([' ', '\t']))
+        .map(str::trim_start)
+        .unwrap_or(trimmed);
+
+    let key_len = body.find(|c: char| !is_key_char(c)).unwrap_or(body.len());
+    let key = &body[..key_len];
+
+    if !is_valid_key(key) {
+        return None;
+    }
+
+    let after_key = body[key_len..].trim_start_matches([' ', '\t']);
+    let after_eq = after_key.strip_prefix('=')?;
+
+    Some((line.len() - after_eq.len(), key))
+}
+
+// Parses a value starting right after `=`, returning its parts, where the value ends before any
+// trailing comment, and the length up to the line break
+fn parse_value(input: &str) -> Result<(Vec<Part>, usize, usize), String> {
+    let start = input.len() - input.trim_start_matches([' ', '\t']).len();
+    let value = &input[start..];
+
+    let (parts, value_len) = match value.chars().next() {
+        Some('\'') => {
+            let end = value[1..].find('\'').ok_or("Unterminated single quote")?;
+            (vec![Part::Text(value[1..end + 1].to_string())], end + 2)
+        }
+        Some('"') => parse_double_quoted(&value[1..]).map(|(parts, len)| (parts, len + 1))?,
+        _ => {
+            let line_end = value.find(['\r', '\n']).unwrap_or(value.len());
+            let line = &value[..line_end];
+
+            // A `#` only starts a comment after whitespace, so `pa#ss` stays intact
+            let comment = line
+                .char_indices()
+                .find(|&(i, c)| c == '#' && (i == 0 || line[..i].ends_with([' ', '\t'])))
+                .map_or(line.len(), |(i, _)| i);
+
+            let text = line[..comment].trim_end();
+            return Ok((interpolate(text)?, start + text.len(), start + line_end));
+        }
+    };
+
+    // Only whitespace or a comment may follow a closing quote
+    let rest = &value[value_len..];
+    let line_end = rest.find(['\r', '\n']).unwrap_or(rest.len());
+    let trailing = rest[..line_end].trim_start();
+
+    if !trailing.is_empty() && !trailing.starts_with('#') {
+        return Err(format!("Unexpected `{}` after quoted value", trailing));
+    }
+
+    Ok((parts, start + value_len, start + value_len + line_end))
+}
+
+// Parses the inside of a double-quoted value, returning its parts and the length including the closing quote
+fn parse_double_quoted(input: &str) -> Result<(Vec<Part>, usize), String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                push_text(&mut parts, text);
+                return Ok((parts, i + 1));
+            }
+            '\\' => match chars.next() {
+                Some((_, 'n')) => text.push('\n'),
+                Some((_, 'r')) => text.push('\r'),
+                Some((_, 't')) => text.push('\t'),
+                Some((_, escaped @ ('"' | '\\' | '$'))) => text.push(escaped),
+                Some((_, other)) => {
+                    text.push('\\');
+                    text.push(other);
+                }
+                None => break,
+            },
+            '$' => {
+                let (var, len) = parse_var(&input[i..])?;
+
+                match var {
+                    Some(var) => {
+                        push_text(&mut parts, std::mem::take(&mut text));
+                        parts.push(var);
+                    }
+                    None => text.push('$'),
+                }
+
+                while chars.peek().is_some_and(|&(j, _)| j < i + len) {
+                    chars.next();
+                }
+            }
+            _ => text.push(c),
+        }
+    }
+
+    Err("Unterminated double quote".to_string())
+}
+
+fn interpolate(input: &str) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut rest = input;
+
+    while let Some(i) = rest.find('$') {
+        text.push_str(&rest[..i]);
+
+        match parse_var(&rest[i..])? {
+            (Some(var), len) => {
+                push_text(&mut parts, std::mem::take(&mut text));
+                parts.push(var);
+                rest = &rest[i + len..];
+            }
+            (None, len) => {
+                text.push('$');
+                rest = &rest[i + len..];
+            }
+        }
+    }
+
+    text.push_str(rest);
+    push_text(&mut parts, text);
+    Ok(parts)
+}
+
+// Parses `${NAME}`, `${NAME:-default}` or `$NAME` at the start of `input`, which begins with `$`.
+// `$$` and a `$` not followed by a name are a literal `$`, returned as None with the length to skip.
+// Names start with a letter or `_` like keys do, so `$1` stays literal.
+fn parse_var(input: &str) -> Result<(Option<Part>, usize), String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let is_name_start = |c: char| c.is_ascii_alphabetic() || c == '_';
+
+    if input.starts_with("$$") {
+        return Ok((None, 2));
+    }
+
+    if let Some(braced) = input.strip_prefix("${") {
+        let end = braced.find('}').ok_or("Unterminated `${`")?;
+        let (name, default) = match braced[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default.to_string())),
+            None => (&braced[..end], None),
+        };
+
+        if !name.starts_with(is_name_start) || !name.chars().all(is_name) {
+            return Err(format!("Invalid variable name `{}`", name));
+        }
+
+        return Ok((Some(Part::Var { name: name.to_string(), default }), end + 3));
+    }
+
+    if !input[1..].starts_with(is_name_start) {
+        return Ok((None, 1));
+    }
+
+    let name_len = input[1..].find(|c: char| !is_name(c)).unwrap_or(input.len() - 1);
+
+    Ok((Some(Part::Var { name: input[1..name_len + 1].to_string(), default: None }), name_len + 1))
+}
+
+fn push_text(parts: &mut Vec<Part>, text: String) {
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+}
+
+// Writes a value so it parses back to exactly `value`, without interpolation
+fn quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:@+,=%~^".contains(c);
+
+    if value.chars().all(plain) {
+        return value.to_string();
+    }
+
+    if !value.contains('\'') {
+        return format!("'{value}'");
+    }
+
+    let mut quoted = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(content: &str) -> Vec<(String, String)> {
+        Dotenv::parse(content).unwrap().resolve(|_| None)
+    }
+
+    fn value(content: &str, key: &str) -> String {
+        resolve(content).into_iter().rev().find(|(k, _)| k == key).map(|(_, v)| v).unwrap()
+    }
+
+    #[test]
+    fn single_quotes_are_literal() {
+        assert_eq!(value("A='x $B ${C} \\n # y'\n", "A"), "x $B ${C} \\n # y");
+    }
+
+    #[test]
+    fn double_quotes_apply_escapes() {
+        assert_eq!(value(r#"A="a\nb\r\tc \"q\" \\ \$B \x""#, "A"), "a\nb\r\tc \"q\" \\ $B \\x");
+    }
+
+    #[test]
+    fn quoted_values_span_lines() {
+        let content = "A=\"one\ntwo\"\nB='three\nfour'\nC=5\n";
+
+        assert_eq!(resolve(content), vec![
+            ("A".to_string(), "one\ntwo".to_string()),
+            ("B".to_string(), "three\nfour".to_string()),
+            ("C".to_string(), "5".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn export_prefix() {
+        let doc = Dotenv::parse("export A=1\n  export\tB = 2\nexportC=3\n").unwrap();
+        let keys: Vec<&str> = doc.entries().map(Entry::key).collect();
+
+        assert_eq!(keys, ["A", "B", "exportC"]);
+        assert_eq!(doc.get("B").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn comments() {
+        let content = "# full line\nA=pa#ss # comment\nB=\"x # y\" # comment\nC=#empty\nD=1\t# tab\n";
+
+        assert_eq!(resolve(content), vec![
+            ("A".to_string(), "pa#ss".to_string()),
+            ("B".to_string(), "x # y".to_string()),
+            ("C".to_string(), String::new()),
+            ("D".to_string(), "1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn interpolation() {
+        let doc = Dotenv::parse("A=a\nB=${A}-$A-${MISSING:-def}-${ENV}-$ENV.x\nC=\"${B:-unused}\"\n").unwrap();
+        let vars = doc.resolve(|name| (name == "ENV").then(|| "env".to_string()));
+
+        assert_eq!(vars[1].1, "a-a-def-env-env.x");
+        assert_eq!(vars[2].1, "a-a-def-env-env.x");
+        assert_eq!(doc.get("B").as_deref(), Some("${A}-${A}-${MISSING:-def}-${ENV}-${ENV}.x"));
+    }
+
+    #[test]
+    fn double_dollar_is_literal() {
+        let doc = Dotenv::parse("A=price$$5\nB=\"$$HOME and $$$$\"\nC='$$'\nD=cost $ 1\n").unwrap();
+        let vars = doc.resolve(|_| Some("set".to_string()));
+
+        assert_eq!(vars[0].1, "price$5");
+        assert_eq!(vars[1].1, "$HOME and $$");
+        assert_eq!(vars[2].1, "$$");
+        assert_eq!(vars[3].1, "cost $ 1");
+    }
+
+    #[test]
+    fn digit_after_dollar_is_literal() {
+        let doc = Dotenv::parse("A=pa$1word
+B=\"$9_x $_x\"\n").unwrap();
+        let vars = doc.resolve(|_| Some("set".to_string()));
+
+        assert_eq!(vars[0].1, "pa$1word");
+        assert_eq!(vars[1].1, "$9_x set");
+        assert_eq!(doc.get("A").as_deref(), Some("pa$1word"));
+        assert!(Dotenv::parse("A=${1}\n").is_err());
+    }
+
+    #[test]
+    fn round_trips_byte_for_byte() {
+        let content = "# header\r\n\r\nexport A = 'x' # note\r\nnot an assignment\nB=\"multi\nline\"   \n  C=${A:-d} # c\n=orphan\nD=last";
+
+        assert_eq!(Dotenv::parse(content).unwrap().to_string(), content);
+    }
+
+    #[test]
+    fn set_keeps_trailing_comment() {
+        let mut doc = Dotenv::parse("A=1 # keep\nB='2'  # also\n").unwrap();
+        doc.set("A", "new");
+        doc.set("B", "two words");
+
+        assert_eq!(doc.to_string(), "A=new # keep\nB='two words'  # also\n");
+    }
+
+    #[test]
+    fn set_quotes_values_that_parse_back() {
+        for value in ["plain", "it's", "$HOME ${X}", "line\nbreak\r", "it's $HOME\n\"quoted\" \\", "a # b", "", " padded "] {
+            let mut doc = Dotenv::parse("").unwrap();
+            doc.set("A", value);
+
+            let parsed = Dotenv::parse(&doc.to_string()).unwrap();
+            assert_eq!(parsed.resolve(|_| Some("interpolated".to_string()))[0].1, value, "{}", doc);
+        }
+
+        let mut doc = Dotenv::default();
+        doc.set("A", "it's $HOME\n");
+        assert_eq!(doc.to_string(), "A=\"it's \\$HOME\\n\"\n");
+    }
+
+    #[test]
+    fn set_appends_after_last_line() {
+        let mut doc = Dotenv::parse("A=1").unwrap();
+        doc.set("B", "2");
+        doc.set("A", "3");
+
+        assert_eq!(doc.to_string(), "A=3\nB=2\n");
+        assert!(doc.remove("A"));
+        assert!(!doc.remove("A"));
+        assert_eq!(doc.to_string(), "B=2\n");
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let cases = [
+            ("A=1\nB='open\n", "Line 2: Unterminated single quote"),
+            ("A=\"one\ntwo\"\nB=\"open", "Line 3: Unterminated double quote"),
+            ("A='x' y\n", "Line 1: Unexpected `y` after quoted value"),
+            ("A=${B\n", "Line 1: Unterminated `${`"),
+            ("A=${B-C}\n", "Line 1: Invalid variable name `B-C`"),
+        ];
+
+        for (content, error) in cases {
+            assert_eq!(Dotenv::parse(content).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn set_raw_value_rejects_trailing_text() {
+        let mut doc = Dotenv::parse("A=1\n").unwrap();
+        let entry = doc.entries_mut().next().unwrap();
+
+        assert!(entry.set_raw_value("'x' y").is_err());
+        entry.set_raw_value("\"y\"").unwrap();
+        assert_eq!(doc.to_string(), "A=\"y\"\n");
+    }
+}
@@ -1,8 +1,10 @@
 use octocrab::Octocrab;
 use log::info;
+use serde::Deserialize;
 use std::path::PathBuf;
 use directories::ProjectDirs;
 
+pub mod dotenv;
 pub mod models;
 
 pub fn get_config_dir() -> Option<PathBuf> {
@@ -35,10 +37,53 @@ pub fn parse_github_repo(url: &str) -> Option<(String, String)> {
     Some((owner.to_string(), repo.to_string()))
 }
 
+#[derive(Deserialize)]
+struct SshKey {
+    key: String,
+}
+
 fn build_octocrab(token: &str) -> octocrab::Result<Octocrab> {
-    Octocrab::builder().personal_token(token).build()
+    let builder = Octocrab::builder().personal_token(token);
+
+    // Allows pointing at GitHub Enterprise or a mock API
+    match std::env::var("ENVA_GITHUB_API_URL") {
+        Ok(url) => builder.base_uri(url)?.build(),
+        Err(_) => builder.build(),
+    }
+}
+
+pub async fn fetch_ssh_keys(token: &str, login: &str) -> Result<Vec<String>, String> {
+    let octocrab = build_octocrab(token).map_err(|e| e.to_string())?;
+
+    let keys: Vec<SshKey> = octocrab
+        .get(format!("/users/{login}/keys"), None::<&()>)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(keys.into_iter().map(|k| k.key).collect())
+}
+
+pub async fn fetch_team_members(token: &str, org: &str, team: &str) -> Result<Vec<String>, String> {
+    let octocrab = build_octocrab(token).map_err(|e| e.to_string())?;
+
+    let page = octocrab
+        .teams(org)
+        .members(team)
+        .per_page(100)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let members = octocrab
+        .all_pages(page)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(members.into_iter().map(|m| m.login).collect())
 }
-pub async fn check_ownership(token: &str, repo_url: &str) -> Result<bool, String> {
+
+// Checks that the token's user may access the repo and returns their login
+pub async fn check_ownership(token: &str, repo_url: &str) -> Result<String, String> {
     let octocrab = build_octocrab(token).map_err(|e| e.to_string())?;
 
     info!("Octocrab built successfully");
@@ -57,7 +102,7 @@ pub async fn check_ownership(token: &str, repo_url: &str) -> Result<bool, String
             // Allow if user has write permission
             if perms.push {
                 info!("User {} has write permission to {}/{}", username, owner, repo_name);
-                return Ok(true);
+                return Ok(username);
             }
 
             // Otherwise, check if user is org member with at least read permission
@@ -88,5 +133,26 @@ pub async fn check_ownership(token: &str, repo_url: &str) -> Result<bool, String
         }
     }
 
-    Ok(true)
+    Ok(username)
+}
+
+// Checks that the token's user can push to the repo and returns their login. Operations that
+// replace stored snapshots need this, read access alone isn't enough.
+pub async fn check_push_access(token: &str, repo_url: &str) -> Result<String, String> {
+    let (owner, repo_name) = parse_github_repo(repo_url).ok_or_else(|| format!("Failed to parse repo URL: {}", repo_url))?;
+
+    let octocrab = build_octocrab(token).map_err(|e| e.to_string())?;
+    let username = octocrab.current().user().await.map_err(|e| e.to_string())?.login;
+
+    let repo = octocrab
+        .repos(&owner, &repo_name)
+        .get()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !repo.permissions.is_some_and(|perms| perms.push) {
+        return Err(format!("User {} needs push access to {}/{} for this", username, owner, repo_name));
+    }
+
+    Ok(username)
 }
@@ -1,17 +1,64 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+// Env files are arbitrary bytes, sent as base64 so certificates and non-UTF-8 files survive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "EnvFileRepr")]
+pub struct EnvFile {
+    #[serde(with = "base64_bytes")]
+    pub content: Vec<u8>,
+    // Unix permission bits of the file, restored on fetch
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+// Older clients send each file as a plain string holding its text or encrypted blob
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EnvFileRepr {
+    Legacy(String),
+    File {
+        #[serde(with = "base64_bytes")]
+        content: Vec<u8>,
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+}
+
+impl From<EnvFileRepr> for EnvFile {
+    fn from(repr: EnvFileRepr) -> Self {
+        match repr {
+            EnvFileRepr::Legacy(content) => EnvFile { content: content.into_bytes(), mode: None },
+            EnvFileRepr::File { content, mode } => EnvFile { content, mode },
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CommitRequest {
     pub repo_url: String,
     pub branch: String,
     pub commit_id: String,
-    pub env_files: HashMap<String, String>,
+    pub env_files: HashMap<String, EnvFile>,
+    #[serde(default)]
+    pub key_check: Option<String>,
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    // Sealed snapshot: names and contents encrypted together, env_files is empty
+    #[serde(default, with = "base64_bytes_option")]
+    pub archive: Option<Vec<u8>>,
+    // Revision the manifest was signed for, rejected if another upload took it first
+    #[serde(default)]
+    pub revision: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommitResponse {
     pub success: bool,
+    // Revision of the commit's snapshot created by this upload
+    #[serde(default)]
+    pub revision: Option<u32>,
     pub error: Option<String>,
 }
 
@@ -19,12 +66,24 @@ pub struct CommitResponse {
 pub struct FetchRequest {
     pub repo_url: String,
     pub commit_id: String,
+    // Latest revision when not set
+    #[serde(default)]
+    pub revision: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FetchResponse {
     pub success: bool,
-    pub env_files: Option<HashMap<String, String>>,
+    pub env_files: Option<HashMap<String, EnvFile>>,
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    #[serde(default, with = "base64_bytes_option")]
+    pub archive: Option<Vec<u8>>,
+    #[serde(default)]
+    pub revision: Option<u32>,
+    // Files the server stored in the pre-envelope format, which have no associated data to check
+    #[serde(default)]
+    pub legacy_blobs: Vec<String>,
     pub error: Option<String>,
 }
 
@@ -37,5 +96,199 @@ pub struct CheckCommitRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CheckCommitResponse {
     pub exists: bool,
+    // Latest revision of the snapshot
+    #[serde(default)]
+    pub revision: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListRequest {
+    pub repo_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommitInfo {
+    pub commit_id: String,
+    pub branch: String,
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    // Every stored revision of the snapshot, oldest first
+    #[serde(default)]
+    pub revisions: Vec<RevisionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub revision: u32,
+    // Unknown for snapshots stored before revisions were tracked
+    #[serde(default)]
+    pub uploaded_at: Option<DateTime<Utc>>,
+    // GitHub login of the uploader
+    #[serde(default)]
+    pub uploader: Option<String>,
+    // Branch the revision was uploaded from, unknown on older servers
+    #[serde(default)]
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListResponse {
+    pub success: bool,
+    pub commits: Option<Vec<CommitInfo>>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct KeyCheckRequest {
+    pub repo_url: String,
+    // Registered when the repo has no key check yet
+    pub key_check: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyCheckResponse {
+    pub success: bool,
+    // Server-side policy, clients must encrypt before committing when set
+    #[serde(default)]
+    pub encrypted: bool,
+    pub key_check: Option<String>,
+    // Bumped on every rekey, recorded in each blob so clients know which key it needs
+    #[serde(default)]
+    pub key_generation: u32,
+    // Snapshots from before the envelope format remain, which need the legacy fixed-salt key
+    #[serde(default)]
+    pub legacy_blobs: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RekeyRequest {
+    pub repo_url: String,
+    // Key check the snapshots were read under, the rekey is rejected if it changed meanwhile
+    pub old_key_check: Option<String>,
+    pub key_check: String,
+    pub key_generation: u32,
+    // Must cover every snapshot of the repo, all of them are replaced together
+    pub snapshots: Vec<RekeySnapshot>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RekeySnapshot {
+    pub branch: String,
+    pub commit_id: String,
+    pub revision: u32,
+    pub env_files: HashMap<String, EnvFile>,
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    #[serde(default, with = "base64_bytes_option")]
+    pub archive: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RekeyResponse {
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BranchesRequest {
+    pub repo_url: String,
+}
+
+// Latest snapshot uploaded from a branch
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BranchInfo {
+    pub branch: String,
+    pub commit_id: String,
+    // Not known for branches whose snapshots predate upload times
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BranchesResponse {
+    pub success: bool,
+    pub branches: Option<Vec<BranchInfo>>,
     pub error: Option<String>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RollbackRequest {
+    pub repo_url: String,
+    pub commit_id: String,
+    // Copied as the new latest revision, so the rollback itself can be undone
+    pub revision: u32,
+    // Manifest of the copy, signed for the revision it becomes
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    #[serde(default)]
+    pub new_revision: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackResponse {
+    pub success: bool,
+    pub revision: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub repo: String,
+    pub commit_id: String,
+    pub parent: Option<String>,
+    pub author: String,
+    // file name: hex SHA-256 of the content as uploaded
+    pub files: BTreeMap<String, String>,
+    // file name: recorded Unix permission bits, for files that have them
+    #[serde(default)]
+    pub modes: BTreeMap<String, u32>,
+    // Manifests signed before revisions existed belong to the first one
+    #[serde(default = "first_revision")]
+    pub revision: u32,
+}
+
+fn first_revision() -> u32 {
+    1
+}
+
+// The manifest is kept as the exact JSON that was signed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub manifest: String,
+    pub signer: String,
+    pub signature: String,
+}
+
+mod base64_bytes {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+mod base64_bytes_option {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&STANDARD.encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|encoded| STANDARD.decode(encoded).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}